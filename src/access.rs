@@ -0,0 +1,109 @@
+//! Access and dirty bit harvesting for working-set estimation.
+//!
+//! With the `access_tracking` feature user entries are created not accessed and, when
+//! writable, clean. The hardware or `handle_access_fault` marks them on use, and
+//! `harvest_access_bits` collects and clears the marks of a range.
+//!
+//! The boot code enables the hardware updates where they are implemented and records the
+//! outcome, see `hw_access_dirty`: `activate_kernel_vspace` on riscv, `init_mmu_registers` on
+//! aarch64. Without them the marks are set in software, on the faults
+//! taken by the unmarked entries.
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use rel4_arch::basic::VPtr;
+use sel4_common::sel4_config::SEL4_PAGE_BITS;
+
+use crate::arch::{invalidate_tlb_by_asid, invalidate_tlb_by_asid_va, lookup_vspace_slot};
+use crate::utils::for_each_leaf_mut;
+use crate::vaddr::{check_user_range, check_user_vaddr, vaddr_error_t};
+use crate::{asid_t, PTE};
+
+const BITS_PER_WORD: usize = usize::BITS as usize;
+
+/// Whether the hardware sets both the accessed and the dirty marks.
+static HW_ACCESS_DIRTY: AtomicBool = AtomicBool::new(false);
+
+/// Record the result of `enable_hw_access_dirty`, done once at boot.
+pub(crate) fn set_hw_access_dirty(enabled: bool) {
+    HW_ACCESS_DIRTY.store(enabled, Ordering::Relaxed);
+}
+
+/// Whether the hardware sets both the accessed and the dirty marks. If it does, a fault is never
+/// caused by access tracking and `handle_access_fault` leaves it to the caller.
+#[inline]
+pub fn hw_access_dirty() -> bool {
+    HW_ACCESS_DIRTY.load(Ordering::Relaxed)
+}
+
+/// Set the bits of the 4 KiB pages of `[start, end)` in a bitmap starting at `base`.
+fn set_page_bits(bitmap: &mut [usize], base: usize, start: usize, end: usize) {
+    let first = (start.max(base) - base) >> SEL4_PAGE_BITS;
+    let last = ((end - base) >> SEL4_PAGE_BITS).min(bitmap.len() * BITS_PER_WORD);
+    for page in first..last {
+        bitmap[page / BITS_PER_WORD] |= bit!(page % BITS_PER_WORD);
+    }
+}
+
+/// Collect the accessed and dirty pages of `[start, end)` and clear their bits.
+///
+/// `accessed` and `dirty` get one bit per 4 KiB page from `start`, a large leaf sets the bits of
+/// all its pages. The TLB entries of `asid` are invalidated if any bit was cleared, so the next
+/// accesses mark the entries again. The range has to be page aligned and in the user range, and
+/// the bitmaps long enough to cover it.
+pub fn harvest_access_bits(
+    vspace_root: *mut PTE,
+    asid: asid_t,
+    start: VPtr,
+    end: VPtr,
+    accessed: &mut [usize],
+    dirty: &mut [usize],
+) -> Result<(), vaddr_error_t> {
+    check_user_range(start, end)?;
+    let pages = (end.raw() - start.raw()) >> SEL4_PAGE_BITS;
+    if accessed.len() * BITS_PER_WORD < pages || dirty.len() * BITS_PER_WORD < pages {
+        return Err(vaddr_error_t::BitmapTooShort);
+    }
+    accessed.fill(0);
+    dirty.fill(0);
+    let (base, end) = (start.raw(), end.raw());
+    if for_each_leaf_mut(vspace_root, asid, base, end, |pte, leaf_start, leaf_end| {
+        let leaf_end = leaf_end.min(end);
+        // Read and clear in one step, so a bit set by the hardware is either seen or kept.
+        let ret = pte.fetch_update(|pte| pte.accessed_dirty_cleared());
+        let (Ok(old) | Err(old)) = ret;
+        if old.is_accessed() {
+            set_page_bits(accessed, base, leaf_start, leaf_end);
+        }
+        if old.is_dirty() {
+            set_page_bits(dirty, base, leaf_start, leaf_end);
+        }
+        ret.is_ok()
+    }) {
+        invalidate_tlb_by_asid(asid);
+    }
+    Ok(())
+}
+
+/// Handle a fault on `vaddr` caused by a leaf that is not accessed yet, or written while clean.
+///
+/// Returns `Ok(true)` if the leaf was marked and the faulting access can be retried, always
+/// `Ok(false)` when the marks are set by the hardware.
+pub fn handle_access_fault(
+    vspace_root: *mut PTE,
+    asid: asid_t,
+    vaddr: VPtr,
+    write: bool,
+) -> Result<bool, vaddr_error_t> {
+    check_user_vaddr(vaddr)?;
+    if hw_access_dirty() {
+        return Ok(false);
+    }
+    let lu_ret = lookup_vspace_slot(vspace_root, vaddr);
+    let slot = unsafe { &*lu_ret.ptSlot };
+    slot.split_group(asid, vaddr, lu_ret.ptBitsLeft);
+    if slot.fetch_update(|pte| pte.accessed_marked(write)).is_err() {
+        return Ok(false);
+    }
+    invalidate_tlb_by_asid_va(asid, vaddr);
+    Ok(true)
+}
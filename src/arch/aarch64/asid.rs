@@ -13,7 +13,10 @@ use crate::{asid_pool_t, asid_t, findVSpaceForASID_ret, set_vm_root};
 use sel4_common::structures_gen::asid_map;
 
 use super::asid_pool_from_addr;
+#[cfg(not(feature = "hypervisor"))]
 use super::machine::invalidate_local_tlb_asid;
+#[cfg(feature = "hypervisor")]
+use super::vmid::invalidate_vmid_entry;
 
 pub(crate) static mut armKSASIDTable: [usize; bit!(ASID_HIGH_BITS)] = [0; bit!(ASID_HIGH_BITS)];
 
@@ -74,9 +77,14 @@ pub fn delete_asid(asid: usize, vspace: *mut PTE, capability: &cap) -> Result<()
         match asidmap.clone().splay() {
             asid_map_Splayed::asid_map_vspace(data) => {
                 if data.get_vspace_root() == vspace as u64 {
+                    #[cfg(not(feature = "hypervisor"))]
                     invalidate_local_tlb_asid(asid);
+                    #[cfg(feature = "hypervisor")]
+                    invalidate_vmid_entry(asid);
                     pool[asid & mask_bits!(ASID_LOW_BITS)] =
                         asid_map_asid_map_none::new().unsplay();
+                    #[cfg(feature = "reverse_map")]
+                    crate::rmap_remove_asids(asid, 1);
                     return set_vm_root(capability);
                 }
             }
@@ -99,10 +107,15 @@ pub fn delete_asid_pool(
         for offset in 0..bit!(ASID_LOW_BITS) {
             let asidmap = &pool[offset];
             if asidmap.get_tag() == asid_map_tag::asid_map_asid_map_vspace {
+                #[cfg(not(feature = "hypervisor"))]
                 invalidate_local_tlb_asid(asid_base + offset);
+                #[cfg(feature = "hypervisor")]
+                invalidate_vmid_entry(asid_base + offset);
             }
         }
         set_asid_pool_by_index(asid_base >> ASID_LOW_BITS, 0);
+        #[cfg(feature = "reverse_map")]
+        crate::rmap_remove_asids(asid_base, bit!(ASID_LOW_BITS));
         return set_vm_root(default_vspace_cap);
     }
     Ok(())
@@ -1,10 +1,10 @@
-use rel4_arch::basic::{PPtr, VPtr};
+use rel4_arch::basic::{PAddr, PPtr, PRegion, VPtr};
 use sel4_common::{
     arch::{
         config::{PADDR_BASE, PADDR_TOP, PPTR_BASE, PPTR_TOP},
         vm_rights_t,
     },
-    sel4_config::{ARM_LARGE_PAGE, ARM_SMALL_PAGE, PUD_INDEX_BITS, SEL4_LARGE_PAGE_BITS},
+    sel4_config::{PT_INDEX_BITS, SEL4_LARGE_PAGE_BITS, SEL4_PAGE_BITS},
     structures_gen::{cap, cap_frame_cap, cap_page_table_cap, cap_vspace_cap},
     utils::convert_to_mut_type_ref,
 };
@@ -13,10 +13,21 @@ use crate::{
     arch::VAddr, asid_t, get_kernel_page_directory_base_by_index, get_kernel_page_table_base,
     get_kernel_page_upper_directory_base, kpptr_to_paddr, mair_types,
     set_kernel_page_directory_by_index, set_kernel_page_global_directory_by_index,
-    set_kernel_page_table_by_index, set_kernel_page_upper_directory_by_index, vm_attributes_t, PTE,
+    set_kernel_page_table_by_index, set_kernel_page_upper_directory_by_index, vm_attributes_t,
+    KernelImageLayout, KernelSection, PageSize, ReclaimedRegions, PDE, PGDE, PTE, PUDE,
 };
 
-use super::{map_kernel_devices, page_slice};
+#[cfg(feature = "hypervisor")]
+use super::s2ap_t;
+use super::{
+    clear_contiguous_hint, get_kernel_image_page_table_base_by_index,
+    get_kernel_page_directory_by_index, invalidate_local_kernel_tlb, map_kernel_devices,
+    page_slice, set_contiguous_hint, set_contiguous_hints, set_kernel_image_page_table_by_index,
+    PTEFlags, KPT_START_LEVEL, UPT_LEVELS,
+};
+use crate::kernel_image::KERNEL_IMAGE_BOUNDARIES;
+use crate::utils::PageAligned;
+use core::mem::size_of;
 
 #[derive(PartialEq, Eq, Debug)]
 enum find_type {
@@ -28,73 +39,193 @@ enum find_type {
 #[no_mangle]
 #[link_section = ".boot.text"]
 pub fn rust_map_kernel_window() {
-    set_kernel_page_global_directory_by_index(
-        (VAddr(PPTR_BASE)).get_kpt_index(0),
-        PTE::pte_new_table(kpptr_to_paddr(get_kernel_page_upper_directory_base())),
-    );
+    // Without level 0 (64 KiB granule) the PUD is the root of the kernel vspace.
+    if KPT_START_LEVEL == 0 {
+        set_kernel_page_global_directory_by_index(
+            (VAddr(PPTR_BASE)).get_kpt_index(0),
+            PGDE::new_table(kpptr_to_paddr(get_kernel_page_upper_directory_base())),
+        );
+    }
 
     let mut idx = VAddr(PPTR_BASE).get_kpt_index(1);
     while idx < VAddr(PPTR_TOP).get_kpt_index(1) {
         set_kernel_page_upper_directory_by_index(
             idx,
-            PTE::pte_new_table(kpptr_to_paddr(get_kernel_page_directory_base_by_index(idx))),
+            PUDE::new_table(kpptr_to_paddr(get_kernel_page_directory_base_by_index(idx))),
         );
         idx += 1;
     }
 
     let mut vaddr = PPTR_BASE;
     let mut paddr = PADDR_BASE;
-    let shareable = if cfg!(feature = "enable_smp") { 3 } else { 0 };
+    let layout = KernelImageLayout::current();
+    let mut image_pt_used = 0;
 
+    // The kernel image lives in the physical window, map it W^X: `.text` RX, `.rodata` R
+    // and everything else RW-NX. Level 2 blocks crossing a section boundary are split into pages.
     while paddr < PADDR_TOP {
-        #[cfg(feature = "hypervisor")]
-        set_kernel_page_directory_by_index(
-            VAddr(vaddr).get_kpt_index(1),
-            VAddr(vaddr).get_kpt_index(2),
-            PTE::pte_new_page(
-                0,
-                paddr!(paddr),
-                0,
-                1,
-                shareable,
-                0,
-                mair_types::NORMAL as usize,
-            ),
-        );
-        #[cfg(not(feature = "hypervisor"))]
+        let pde = match layout.uniform_section(vaddr, vaddr + bit!(SEL4_LARGE_PAGE_BITS)) {
+            Some(section) => kernel_window_block(paddr!(paddr), section),
+            None => {
+                assert!(image_pt_used < KERNEL_IMAGE_BOUNDARIES);
+                for idx in 0..bit!(PT_INDEX_BITS) {
+                    let offset = idx << SEL4_PAGE_BITS;
+                    set_kernel_image_page_table_by_index(
+                        image_pt_used,
+                        idx,
+                        kernel_window_page(
+                            paddr!(paddr + offset),
+                            layout.section_of(vaddr + offset),
+                        ),
+                    );
+                }
+                image_pt_used += 1;
+                PDE::new_table(kpptr_to_paddr(get_kernel_image_page_table_base_by_index(
+                    image_pt_used - 1,
+                )))
+            }
+        };
         set_kernel_page_directory_by_index(
             VAddr(vaddr).get_kpt_index(1),
             VAddr(vaddr).get_kpt_index(2),
-            PTE::pte_new_page(
-                1,
-                paddr!(paddr),
-                0,
-                1,
-                shareable,
-                0,
-                mair_types::NORMAL as usize,
-            ),
+            pde,
         );
 
         vaddr += bit!(SEL4_LARGE_PAGE_BITS);
         paddr += bit!(SEL4_LARGE_PAGE_BITS)
     }
 
+    // Let the TLB cache a contiguous group of blocks or pages with the same permissions at once.
+    let mut idx = VAddr(PPTR_BASE).get_kpt_index(1);
+    while idx < VAddr(PPTR_TOP).get_kpt_index(1) {
+        set_contiguous_hints(
+            get_kernel_page_directory_base_by_index(idx) as *const PTE,
+            bit!(PT_INDEX_BITS),
+            SEL4_LARGE_PAGE_BITS,
+        );
+        idx += 1;
+    }
+    for pt in 0..image_pt_used {
+        set_contiguous_hints(
+            get_kernel_image_page_table_base_by_index(pt) as *const PTE,
+            bit!(PT_INDEX_BITS),
+            SEL4_PAGE_BITS,
+        );
+    }
+
+    // The kernel devices live in the last PD entry of the address space.
+    let top = VAddr(usize::MAX);
     set_kernel_page_upper_directory_by_index(
         VAddr(PPTR_TOP).get_kpt_index(1),
-        PTE::pte_new_table(kpptr_to_paddr(get_kernel_page_directory_base_by_index(
-            bit!(PUD_INDEX_BITS) - 1,
+        PUDE::new_table(kpptr_to_paddr(get_kernel_page_directory_base_by_index(
+            top.get_kpt_index(1),
         ))),
     );
     set_kernel_page_directory_by_index(
-        bit!(PUD_INDEX_BITS) - 1,
-        bit!(PUD_INDEX_BITS) - 1,
-        PTE::pte_new_table(kpptr_to_paddr(get_kernel_page_table_base())),
+        top.get_kpt_index(1),
+        top.get_kpt_index(2),
+        PDE::new_table(kpptr_to_paddr(get_kernel_page_table_base())),
     );
     map_kernel_devices();
     // ffi_call!(map_kernel_devices());
 }
 
+/// Execute-never and access permission flags of a section in the kernel window.
+fn kernel_section_flags(section: KernelSection) -> PTEFlags {
+    // At EL2 bit 54 is the only execute-never bit,
+    // at EL1 UXN keeps EL0 out and PXN stops the kernel itself.
+    #[cfg(feature = "hypervisor")]
+    let (user_xn, kernel_xn) = (PTEFlags::empty(), PTEFlags::UXN);
+    #[cfg(not(feature = "hypervisor"))]
+    let (user_xn, kernel_xn) = (PTEFlags::UXN, PTEFlags::PXN);
+    match section {
+        KernelSection::BootText | KernelSection::Text => PTEFlags::AP_RO | user_xn,
+        KernelSection::RoData => PTEFlags::AP_RO | user_xn | kernel_xn,
+        KernelSection::Data => user_xn | kernel_xn,
+    }
+}
+
+fn kernel_window_block(paddr: PAddr, section: KernelSection) -> PDE {
+    let shareable = if cfg!(feature = "enable_smp") { 3 } else { 0 };
+    let block = PDE::new_2m_block(0, paddr, 0, 1, shareable, 0, mair_types::NORMAL as usize);
+    PDE(block.0 | kernel_section_flags(section).bits())
+}
+
+fn kernel_window_page(paddr: PAddr, section: KernelSection) -> PTE {
+    let shareable = if cfg!(feature = "enable_smp") { 3 } else { 0 };
+    let page = PTE::pte_new_4k_page(0, paddr, 0, 1, shareable, 0, mair_types::NORMAL as usize);
+    PTE(page.0 | kernel_section_flags(section).bits())
+}
+
+/// Give `.boot.text` back once booting is done.
+///
+/// The kernel window is also the physmap of the untyped allocator, so the pages of
+/// `.boot.text` are remapped RW-NX rather than unmapped. Page tables of the kernel image which
+/// only map data afterwards are replaced by blocks. Return the freed physical regions, the
+/// boot text and those page tables, so they can be handed to the untyped allocator.
+///
+/// Not in `.boot.text`, neither is anything it calls.
+pub fn reclaim_boot_text() -> ReclaimedRegions {
+    let layout = KernelImageLayout::current();
+    let (start, end) = layout.boot_text_pages();
+    let mut reclaimed = ReclaimedRegions::new();
+    if start == end {
+        return reclaimed;
+    }
+    reclaimed.push(PRegion::new(kpptr_to_paddr(start), kpptr_to_paddr(end)));
+
+    // Only the permissions change, so no break-before-make is needed.
+    let mut vaddr = start;
+    while vaddr < end {
+        let (idx1, idx2) = (VAddr(vaddr).get_kpt_index(1), VAddr(vaddr).get_kpt_index(2));
+        let pde = get_kernel_page_directory_by_index(idx1, idx2);
+        if pde.is_table() {
+            let pt = pde.next_level_slice();
+            let idx3 = VAddr(vaddr).get_kpt_index(3);
+            if clear_contiguous_hint(&pt[idx3]) {
+                invalidate_local_kernel_tlb();
+            }
+            pt[idx3] = kernel_window_page(kpptr_to_paddr(vaddr), KernelSection::Data);
+            vaddr += bit!(SEL4_PAGE_BITS);
+        } else {
+            // Only 2 MiB regions fully inside `.boot.text` are mapped as blocks.
+            let pd = get_kernel_page_directory_base_by_index(idx1) as *const PTE;
+            if clear_contiguous_hint(unsafe { pd.add(idx2) }) {
+                invalidate_local_kernel_tlb();
+            }
+            set_kernel_page_directory_by_index(
+                idx1,
+                idx2,
+                kernel_window_block(kpptr_to_paddr(vaddr), KernelSection::Data),
+            );
+            vaddr += bit!(SEL4_LARGE_PAGE_BITS);
+        }
+    }
+    invalidate_local_kernel_tlb();
+
+    // A page table left with data only becomes a block, which changes the size of the
+    // translation: break-before-make.
+    let mut block = start & !mask_bits!(SEL4_LARGE_PAGE_BITS);
+    while block < end {
+        let (idx1, idx2) = (VAddr(block).get_kpt_index(1), VAddr(block).get_kpt_index(2));
+        let pde = get_kernel_page_directory_by_index(idx1, idx2);
+        if pde.is_table() && layout.reclaimed_all_data(block, block + bit!(SEL4_LARGE_PAGE_BITS)) {
+            let pt = pde.next_level_paddr();
+            set_kernel_page_directory_by_index(idx1, idx2, PDE::invalid());
+            invalidate_local_kernel_tlb();
+            set_kernel_page_directory_by_index(
+                idx1,
+                idx2,
+                kernel_window_block(kpptr_to_paddr(block), KernelSection::Data),
+            );
+            reclaimed.push(PRegion::new(pt, pt + size_of::<PageAligned<PTE>>()));
+        }
+        block += bit!(SEL4_LARGE_PAGE_BITS);
+    }
+    invalidate_local_kernel_tlb();
+    reclaimed
+}
+
 #[no_mangle]
 pub fn map_kernel_frame(
     paddr: usize,
@@ -103,15 +234,12 @@ pub fn map_kernel_frame(
     attributes: vm_attributes_t,
 ) {
     let uxn = 1;
-    let attr_index: usize;
-    let shareable: usize;
-    if attributes.get_page_cacheable() != 0 {
-        attr_index = mair_types::NORMAL as usize;
-        shareable = if cfg!(feature = "enable_smp") { 3 } else { 0 };
+    let attr_index = attributes.get_attr_index();
+    let shareable = if attr_index.is_cacheable() && cfg!(feature = "enable_smp") {
+        3
     } else {
-        attr_index = mair_types::DEVICE_nGnRnE as usize;
-        shareable = 0;
-    }
+        0
+    };
     set_kernel_page_table_by_index(
         VAddr(vaddr).get_kpt_index(3),
         PTE::pte_new_4k_page(
@@ -121,7 +249,7 @@ pub fn map_kernel_frame(
             1,
             shareable,
             PTE::ap_from_vm_rights_t(vm_rights).bits() >> 6,
-            attr_index,
+            attr_index as usize,
         ),
     );
 }
@@ -132,42 +260,52 @@ pub fn map_it_pt_cap(vspace_cap: &cap_vspace_cap, pt_cap: &cap_page_table_cap) {
     let vspace_root = vspace_cap.clone().get_capVSBasePtr() as usize;
     let vptr = pt_cap.get_capPTMappedAddress() as usize;
     let pt = pt_cap.get_capPTBasePtr() as usize;
-    let target_pte =
-        convert_to_mut_type_ref::<PTE>(find_pt(vspace_root, vptr.into(), find_type::PDE));
-    target_pte.set_next_level_paddr(pptr!(pt).to_paddr());
-    // TODO: move 0x3 into a proper position.
-    target_pte.set_attr(3);
+    let target_pde =
+        convert_to_mut_type_ref::<PDE>(find_pt(vspace_root, vptr.into(), find_type::PDE));
+    *target_pde = PDE::new_table(pptr!(pt).to_paddr());
 }
 
 /// TODO: Write the comments.
 #[no_mangle]
 #[link_section = ".boot.text"]
 pub fn map_it_pd_cap(vspace_cap: &cap_vspace_cap, pd_cap: &cap_page_table_cap) {
-    let pgd = page_slice::<PTE>(pptr!(vspace_cap.get_capVSBasePtr()));
     let pd_addr = pd_cap.get_capPTBasePtr() as usize;
     let vptr: VAddr = (pd_cap.get_capPTMappedAddress() as usize).into();
     assert_eq!(pd_cap.get_capPTIsMapped(), 1);
-    // TODO: move 0x3 into a proper position.
-    assert_eq!(pgd[vptr.pgd_index()].attr(), 0x3);
-    let pud = pgd[vptr.pgd_index()].next_level_slice::<PTE>();
-    pud[vptr.pud_index()] = PTE::new_page(pptr!(pd_addr).to_paddr(), 0x3);
+    let pud = vspace_pud(vspace_cap.get_capVSBasePtr() as usize, vptr);
+    pud[vptr.pud_index()] = PUDE::new_table(pptr!(pd_addr).to_paddr());
 }
 
 /// TODO: Write the comments.
 pub fn map_it_pud_cap(vspace_cap: &cap_vspace_cap, pud_cap: &cap_page_table_cap) {
-    let pgd = page_slice::<PTE>(pptr!(vspace_cap.get_capVSBasePtr()));
+    // Without level 0 the vspace root is the PUD itself.
+    assert_eq!(UPT_LEVELS, 4);
+    let pgd = page_slice::<PGDE>(pptr!(vspace_cap.get_capVSBasePtr()));
     let pud_addr = pud_cap.get_capPTBasePtr() as usize;
     let vptr: VAddr = (pud_cap.get_capPTMappedAddress() as usize).into();
     assert_eq!(pud_cap.get_capPTIsMapped(), 1);
 
-    // TODO: move 0x3 into a proper position.
-    pgd[vptr.pgd_index()] = PTE::new_page(pptr!(pud_addr).to_paddr(), 0x3);
+    pgd[vptr.pgd_index()] = PGDE::new_table(pptr!(pud_addr).to_paddr());
 }
 
 /// TODO: Write the comments.
 #[no_mangle]
 #[link_section = ".boot.text"]
 pub fn map_it_frame_cap(vspace_cap: &cap_vspace_cap, frame_cap: &cap_frame_cap, exec: bool) {
+    #[cfg(feature = "reverse_map")]
+    if let Err(err) = crate::rmap_insert(
+        pptr!(frame_cap.get_capFBasePtr()).to_paddr(),
+        frame_cap.get_capFMappedASID() as usize,
+        vptr!(frame_cap.get_capFMappedAddress() as usize),
+        crate::PageSize::Small,
+    ) {
+        log::error!(
+            "initial thread frame at {:#x} not mapped: {:?}",
+            frame_cap.get_capFMappedAddress(),
+            err
+        );
+        return;
+    }
     let pte = convert_to_mut_type_ref::<PTE>(find_pt(
         vspace_cap.get_capVSBasePtr() as usize,
         (frame_cap.get_capFMappedAddress() as usize).into(),
@@ -177,26 +315,44 @@ pub fn map_it_frame_cap(vspace_cap: &cap_vspace_cap, frame_cap: &cap_frame_cap,
     // TIPS: exec true will be cast to 1 and false to 0.
     let shareable = if cfg!(feature = "enable_smp") { 3 } else { 0 };
     #[cfg(not(feature = "hypervisor"))]
-    let (ng, attr) = (1, 0);
+    let attr = PTE::pte_new_4k_page((!exec) as usize, paddr!(0), 1, 1, shareable, 1, 0);
     #[cfg(feature = "hypervisor")]
-    let (ng, attr) = (1, 0);
-    pte.set_attr(PTE::pte_new_4k_page((!exec) as usize, paddr!(0), ng, 1, shareable, 1, attr).0);
+    let attr = PTE::pte_new_s2_4k_page(
+        (!exec) as usize,
+        paddr!(0),
+        1,
+        shareable,
+        s2ap_t::ReadWrite as usize,
+        mair_types::NORMAL.stage2_mem_attr(),
+    );
+    pte.set_attr(attr.0);
     pte.set_next_level_paddr(pptr!(frame_cap.get_capFBasePtr()).to_paddr());
+    set_contiguous_hint(pte, crate::PageSize::Small.bits());
+}
+
+/// The PUD covering `vptr`, which is the root itself when the walk starts at level 1.
+#[link_section = ".boot.text"]
+fn vspace_pud(vspace_root: usize, vptr: VAddr) -> &'static mut [PUDE] {
+    if UPT_LEVELS == 3 {
+        return page_slice::<PUDE>(pptr!(vspace_root));
+    }
+    let pgd = page_slice::<PGDE>(pptr!(vspace_root));
+    assert!(pgd[vptr.pgd_index()].is_table());
+    pgd[vptr.pgd_index()].next_level_slice()
 }
 
 /// TODO: Write the comments.
 #[link_section = ".boot.text"]
 fn find_pt(vspace_root: usize, vptr: VAddr, ftype: find_type) -> usize {
-    let pgd = page_slice::<PTE>(pptr!(vspace_root));
-    let pud = pgd[vptr.pgd_index()].next_level_slice::<PTE>();
+    let pud = vspace_pud(vspace_root, vptr);
     if ftype == find_type::PUDE {
         return pud[vptr.pud_index()].self_addr();
     }
-    let pd = pud[vptr.pud_index()].next_level_slice::<PTE>();
+    let pd = pud[vptr.pud_index()].next_level_slice();
     if ftype == find_type::PDE {
         return pd[vptr.pd_index()].self_addr();
     }
-    let pt = pd[vptr.pd_index()].next_level_slice::<PTE>();
+    let pt = pd[vptr.pd_index()].next_level_slice();
     assert_eq!(ftype, find_type::PTE);
     pt[vptr.pt_index()].self_addr()
 }
@@ -235,16 +391,15 @@ pub fn create_unmapped_it_frame_cap(pptr: PPtr, use_large: bool) -> cap_frame_ca
 #[no_mangle]
 #[link_section = ".boot.text"]
 pub fn create_it_frame_cap(pptr: PPtr, vptr: VPtr, asid: asid_t, use_large: bool) -> cap_frame_cap {
-    let frame_size;
-    if use_large {
-        frame_size = ARM_LARGE_PAGE;
+    let frame_size = if use_large {
+        PageSize::Large
     } else {
-        frame_size = ARM_SMALL_PAGE;
-    }
+        PageSize::Small
+    };
     cap_frame_cap::new(
         asid as u64,
         pptr.raw() as u64,
-        frame_size as u64,
+        frame_size.to_cap_size() as u64,
         vptr.raw() as u64,
         vm_rights_t::VMReadWrite as u64,
         0,
@@ -0,0 +1,157 @@
+//! Contiguous hint of aligned adjacent leaves. With the 4 KiB granule a group is 16 leaves,
+//! 64 KiB of pages or 32 MiB of 2 MiB blocks. With 16 KiB it is 128 pages (2 MiB) or 32 blocks
+//! (1 GiB), with 64 KiB 32 pages (2 MiB) or 32 blocks (16 GiB).
+//!
+//! The hint lets the TLB cache a whole group in one entry, so every entry of a group has to
+//! agree. Before any entry of a group is modified the hint is cleared on the whole group and
+//! the range of the group invalidated on all cores, otherwise the TLB could keep translating
+//! with the old group.
+use core::arch::asm;
+use core::sync::atomic::Ordering;
+
+use rel4_arch::basic::VPtr;
+
+use super::granule::GRANULE_BITS;
+use super::{dsb, invalidate_tlb_by_asid_range, isb, PTEFlags};
+use crate::{asid_t, check_user_frame, map_error_t, PageSize, PTE};
+
+/// Number of entries of a contiguous group of leaves of `size_bits`.
+#[inline]
+pub const fn cont_entries(size_bits: usize) -> usize {
+    match (GRANULE_BITS, size_bits == GRANULE_BITS) {
+        (12, _) => 16,
+        (14, true) => 128,
+        _ => 32,
+    }
+}
+
+/// Largest group of any level, clearing the hint on a larger aligned range than the group
+/// only costs the neighbouring groups their hint.
+const CONT_MAX_ENTRIES: usize = cont_entries(GRANULE_BITS);
+
+/// The contiguous group of `entries` entries `slot` belongs to.
+#[inline]
+fn cont_group(slot: *const PTE, entries: usize) -> &'static [PTE] {
+    let first = (slot as usize) & !(entries * core::mem::size_of::<PTE>() - 1);
+    unsafe { core::slice::from_raw_parts(first as *const PTE, entries) }
+}
+
+/// Whether `pte` is a leaf at a level whose leaves are `size_bits` large.
+#[inline]
+fn is_leaf_of_size(pte: PTE, size_bits: usize) -> bool {
+    if size_bits == GRANULE_BITS {
+        pte.get_type() == super::pte_tag_t::pte_4k_page as usize
+    } else {
+        pte.get_type() == super::pte_tag_t::pte_page as usize
+    }
+}
+
+/// Set the contiguous hint on the group of `slot` if its leaves of `size_bits` map aligned
+/// contiguous memory with the same attributes. Returns whether the hint was set.
+pub fn set_contiguous_hint(slot: *const PTE, size_bits: usize) -> bool {
+    let entries = cont_entries(size_bits);
+    let group = cont_group(slot, entries);
+    let first = group[0].load();
+    if !is_leaf_of_size(first, size_bits)
+        || first.0 & PTEFlags::CONTIGUOUS.bits() != 0
+        || first.get_page_base_address().raw()
+            & mask_bits!(size_bits + entries.trailing_zeros() as usize)
+            != 0
+    {
+        return false;
+    }
+    let contiguous = group
+        .iter()
+        .enumerate()
+        .all(|(i, pte)| pte.load().0 == first.0 + (i << size_bits));
+    if !contiguous {
+        return false;
+    }
+    for pte in group {
+        pte.atomic()
+            .fetch_or(PTEFlags::CONTIGUOUS.bits(), Ordering::AcqRel);
+        pte.sync_update();
+    }
+    true
+}
+
+/// Set the contiguous hint on every eligible group of a table of `size_bits` leaves.
+pub fn set_contiguous_hints(table: *const PTE, entries: usize, size_bits: usize) {
+    for group in (0..entries).step_by(cont_entries(size_bits)) {
+        set_contiguous_hint(unsafe { table.add(group) }, size_bits);
+    }
+}
+
+/// Clear the contiguous hint on the whole group of `slot`, without touching the TLB.
+/// Returns whether the slot was part of a group.
+pub fn clear_contiguous_hint(slot: *const PTE) -> bool {
+    if unsafe { (*slot).load() }.0 & PTEFlags::CONTIGUOUS.bits() == 0 {
+        return false;
+    }
+    for pte in cont_group(slot, CONT_MAX_ENTRIES) {
+        pte.atomic()
+            .fetch_and(!PTEFlags::CONTIGUOUS.bits(), Ordering::AcqRel);
+        pte.sync_update();
+    }
+    true
+}
+
+impl PTE {
+    /// Split the contiguous group of this entry, a leaf of `size_bits` covering `vaddr` in the
+    /// vspace of `asid`, before it is modified. Only the range of the cleared groups is
+    /// invalidated for that ASID, on all cores.
+    #[inline]
+    pub(crate) fn split_group(&self, asid: asid_t, vaddr: VPtr, size_bits: usize) {
+        if !clear_contiguous_hint(self) {
+            return;
+        }
+        let range_bits = size_bits + CONT_MAX_ENTRIES.trailing_zeros() as usize;
+        let first = vptr!(vaddr.raw() & !mask_bits!(range_bits));
+        invalidate_tlb_by_asid_range(asid, first, CONT_MAX_ENTRIES, size_bits);
+    }
+
+    /// Split the contiguous group of this entry before it is modified, when its address is not
+    /// known. User leaves are split by `split_group` first, this only flushes the whole TLB
+    /// on all cores for a group left to it.
+    #[inline]
+    pub(crate) fn prepare_update(&self) {
+        if !clear_contiguous_hint(self) {
+            return;
+        }
+        dsb();
+        #[cfg(feature = "hypervisor")]
+        unsafe {
+            asm!("tlbi alle2is");
+            asm!("tlbi alle1is");
+        }
+        #[cfg(not(feature = "hypervisor"))]
+        unsafe {
+            asm!("tlbi vmalle1is");
+        }
+        dsb();
+        isb();
+    }
+}
+
+impl PTE {
+    /// Map the user leaf `pte` of `page_size` at `vaddr` of the vspace of `asid` in this slot, as
+    /// found by `lookup_pt_slot`, and set the contiguous hint on its group once the whole
+    /// group maps contiguous memory. The mapping is recorded in the reverse map with
+    /// `reverse_map`. Nothing is mapped at an invalid or misaligned address, or when the
+    /// reverse map is full.
+    pub fn map_user_leaf(
+        &mut self,
+        pte: PTE,
+        page_size: PageSize,
+        asid: asid_t,
+        vaddr: VPtr,
+    ) -> Result<(), map_error_t> {
+        check_user_frame(vaddr, page_size)?;
+        #[cfg(feature = "reverse_map")]
+        crate::rmap::rmap_replace(self.load(), pte, asid, vaddr, page_size)?;
+        self.split_group(asid, vaddr, page_size.bits());
+        self.update(pte);
+        set_contiguous_hint(self, page_size.bits());
+        Ok(())
+    }
+}
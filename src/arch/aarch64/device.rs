@@ -1,5 +1,6 @@
 use super::boot::map_kernel_frame;
-use crate::vm_attributes_t;
+use super::machine::mair_types;
+use crate::{device_memory_t, kernel_device_error_t, kernel_device_frame_t, vm_attributes_t};
 use rel4_arch::basic::PRegion;
 use rel4_arch::paddr;
 use sel4_common::arch::vm_rights_t::VMKernelOnly;
@@ -10,23 +11,62 @@ extern "C" {
     pub(self) fn reserve_region(reg: PRegion) -> bool;
 }
 
+impl From<device_memory_t> for mair_types {
+    fn from(memory_type: device_memory_t) -> Self {
+        match memory_type {
+            device_memory_t::DEVICE_nGnRnE => mair_types::DEVICE_nGnRnE,
+            device_memory_t::DEVICE_nGnRE => mair_types::DEVICE_nGnRE,
+            device_memory_t::DEVICE_GRE => mair_types::DEVICE_GRE,
+            device_memory_t::NORMAL_NC => mair_types::NORMAL_NC,
+        }
+    }
+}
+
 #[no_mangle]
 pub fn map_kernel_devices() {
     unsafe {
         for kernel_frame in kernel_device_frames {
-            let vm_attr: vm_attributes_t = vm_attributes_t(kernel_frame.armExecuteNever as usize);
-            map_kernel_frame(
-                kernel_frame.paddr.raw(),
-                kernel_frame.pptr.raw(),
-                VMKernelOnly,
-                vm_attr,
-            );
-            if kernel_frame.userAvailable == 0 {
-                reserve_region(PRegion::new(
-                    paddr!(kernel_frame.paddr.raw()),
-                    paddr!(kernel_frame.paddr.raw() + bit!(PAGE_BITS)),
-                ));
+            let ret = kernel_device_frame_t::from_platform(
+                kernel_frame.paddr,
+                kernel_frame.pptr,
+                kernel_frame.armExecuteNever != 0,
+                kernel_frame.userAvailable != 0,
+            )
+            .and_then(|frame| map_kernel_device_frame(&frame));
+            if let Err(err) = ret {
+                log::error!(
+                    "kernel device {:#x} not mapped: {:?}",
+                    kernel_frame.paddr.raw(),
+                    err
+                );
             }
         }
     }
 }
+
+/// Map a kernel device region page by page with its memory type, reserving it unless
+/// it is available to user level.
+pub fn map_kernel_device_frame(frame: &kernel_device_frame_t) -> Result<(), kernel_device_error_t> {
+    if (frame.paddr.raw() | frame.pptr.raw()) & mask_bits!(PAGE_BITS) != 0 {
+        return Err(kernel_device_error_t::Misaligned);
+    }
+    let vm_attr =
+        vm_attributes_t::new_arm_with_type(frame.memory_type.into(), false, frame.execute_never);
+    for offset in (0..frame.size()).step_by(bit!(PAGE_BITS)) {
+        map_kernel_frame(
+            frame.paddr.raw() + offset,
+            frame.pptr.raw() + offset,
+            VMKernelOnly,
+            vm_attr,
+        );
+    }
+    if !frame.user_available {
+        unsafe {
+            reserve_region(PRegion::new(
+                paddr!(frame.paddr.raw()),
+                paddr!(frame.paddr.raw() + frame.size()),
+            ));
+        }
+    }
+    Ok(())
+}
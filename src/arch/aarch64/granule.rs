@@ -0,0 +1,227 @@
+//! Translation granule, 4 KiB by default or 16 KiB / 64 KiB with the `arm_granule_16k` /
+//! `arm_granule_64k` feature.
+//!
+//! The granule is the size of a page and of a table, it decides the index bits of each level,
+//! how many levels walk a 48-bit address and the sizes of the blocks:
+//!
+//! | granule | index bits | levels | level 2 block | level 1 block |
+//! |---------|------------|--------|---------------|---------------|
+//! | 4 KiB   | 9          | 0-3    | 2 MiB         | 1 GiB         |
+//! | 16 KiB  | 11         | 0-3    | 32 MiB        | -             |
+//! | 64 KiB  | 13         | 1-3    | 512 MiB       | -             |
+//!
+//! `sel4_common` has to be configured with the same page size, which is checked below.
+use sel4_common::sel4_config::{PT_INDEX_BITS, SEL4_LARGE_PAGE_BITS, SEL4_PAGE_BITS};
+
+use super::isb;
+use super::pa::{PA_LPA2, TCR_PS};
+
+#[cfg(all(feature = "arm_granule_16k", feature = "arm_granule_64k"))]
+compile_error!("`arm_granule_16k` and `arm_granule_64k` are mutually exclusive");
+
+/// Size bits of the granule, a page or a table.
+#[cfg(not(any(feature = "arm_granule_16k", feature = "arm_granule_64k")))]
+pub const GRANULE_BITS: usize = 12;
+#[cfg(feature = "arm_granule_16k")]
+pub const GRANULE_BITS: usize = 14;
+#[cfg(feature = "arm_granule_64k")]
+pub const GRANULE_BITS: usize = 16;
+
+/// Index bits resolved by a table, which holds one 8-byte descriptor per entry.
+pub const GRANULE_INDEX_BITS: usize = GRANULE_BITS - 3;
+/// Number of entries of a table.
+pub const GRANULE_ENTRIES: usize = bit!(GRANULE_INDEX_BITS);
+
+/// Size of the TTBR0 and TTBR1 address ranges.
+pub const VA_BITS: usize = 48;
+
+/// Whether level 1 descriptors can be blocks, only the 4 KiB granule has them without LPA.
+pub const LEVEL1_BLOCKS: bool = GRANULE_BITS == 12;
+
+const _: () = assert!(
+    SEL4_PAGE_BITS == GRANULE_BITS
+        && PT_INDEX_BITS == GRANULE_INDEX_BITS
+        && SEL4_LARGE_PAGE_BITS == granule_level_shift(2),
+    "sel4_common is not configured for the selected translation granule"
+);
+
+/// Bits of the address translated below an entry of ARM level `level` (0-3).
+#[inline]
+pub const fn granule_level_shift(level: usize) -> usize {
+    GRANULE_INDEX_BITS * (3 - level) + GRANULE_BITS
+}
+
+/// ARM level of the root table translating `va_bits` of address.
+#[inline]
+pub const fn granule_start_level(va_bits: usize) -> usize {
+    let mut level = 3;
+    while level > 0 && va_bits > granule_level_shift(level - 1) {
+        level -= 1;
+    }
+    level
+}
+
+/// Index bits resolved by the root table translating `va_bits` of address.
+#[inline]
+pub const fn granule_root_index_bits(va_bits: usize) -> usize {
+    va_bits - granule_level_shift(granule_start_level(va_bits))
+}
+
+/// TG0 field of `TCR_ELx` and `VTCR_EL2`.
+#[cfg(not(any(feature = "arm_granule_16k", feature = "arm_granule_64k")))]
+pub const TCR_TG0: usize = 0b00;
+#[cfg(feature = "arm_granule_16k")]
+pub const TCR_TG0: usize = 0b10;
+#[cfg(feature = "arm_granule_64k")]
+pub const TCR_TG0: usize = 0b01;
+
+/// TG1 field of `TCR_EL1`, encoded differently from TG0.
+#[cfg(not(any(feature = "arm_granule_16k", feature = "arm_granule_64k")))]
+pub const TCR_TG1: usize = 0b10;
+#[cfg(feature = "arm_granule_16k")]
+pub const TCR_TG1: usize = 0b01;
+#[cfg(feature = "arm_granule_64k")]
+pub const TCR_TG1: usize = 0b11;
+
+/// Value of `TCR_EL1` for the kernel: `VA_BITS` for TTBR0 and TTBR1, walks inner shareable
+/// and write-back cacheable, `PA_BITS` of output and 16-bit ASIDs.
+pub const TCR_EL1_VALUE: usize = ((PA_LPA2 as usize) << 59) // DS: LPA2 descriptors
+    | bit!(36)                               // AS: 16-bit ASID
+    | (TCR_PS << 32)
+    | (TCR_TG1 << 30)
+    | (0b11 << 28)                           // SH1: inner shareable
+    | (0b01 << 26)                           // ORGN1: write-back write-allocate
+    | (0b01 << 24)                           // IRGN1: write-back write-allocate
+    | ((64 - VA_BITS) << 16)                 // T1SZ
+    | (TCR_TG0 << 14)
+    | (0b11 << 12)                           // SH0: inner shareable
+    | (0b01 << 10)                           // ORGN0: write-back write-allocate
+    | (0b01 << 8)                            // IRGN0: write-back write-allocate
+    | (64 - VA_BITS); // T0SZ
+
+/// Value of `TCR_EL2` for a kernel running at EL2 without VHE, which only has TTBR0.
+#[cfg(feature = "hypervisor")]
+pub const TCR_EL2_VALUE: usize = ((PA_LPA2 as usize) << 32) // DS: LPA2 descriptors
+    | bit!(31) | bit!(23)                    // RES1
+    | (TCR_PS << 16)
+    | (TCR_TG0 << 14)
+    | (0b11 << 12)                           // SH0: inner shareable
+    | (0b01 << 10)                           // ORGN0: write-back write-allocate
+    | (0b01 << 8)                            // IRGN0: write-back write-allocate
+    | (64 - VA_BITS); // T0SZ
+
+/// `ID_AA64MMFR0_EL1`, the memory model features of the CPU.
+#[inline]
+pub fn id_aa64mmfr0() -> usize {
+    let mmfr0: usize;
+    unsafe {
+        core::arch::asm!("mrs {}, id_aa64mmfr0_el1", out(reg) mmfr0);
+    }
+    mmfr0
+}
+
+/// Whether the CPU implements the granule, for stage 2 as well with the `hypervisor` feature.
+pub fn granule_supported() -> bool {
+    let mmfr0 = id_aa64mmfr0();
+    // TGran4 [31:28] and TGran64 [27:24] are 0xf when unsupported, TGran16 [23:20] is 0.
+    let (stage1, stage2_shift) = match GRANULE_BITS {
+        12 => ((mmfr0 >> 28) & 0xf != 0xf, 40),
+        14 => ((mmfr0 >> 20) & 0xf != 0, 32),
+        _ => ((mmfr0 >> 24) & 0xf != 0xf, 36),
+    };
+    if !stage1 {
+        return false;
+    }
+    if !cfg!(feature = "hypervisor") {
+        return true;
+    }
+    // TGranX_2: 0 same as stage 1, 1 not supported, 2 or more supported.
+    (mmfr0 >> stage2_shift) & 0xf != 1
+}
+
+/// `value` with its PS / IPS field at `ps_shift` lowered to the PARange [3:0] of `mmfr0`, which
+/// uses the same encoding. The kernel built for 52 bits then runs with the 48 bits or fewer the
+/// CPU implements.
+pub const fn tcr_fit_ps(value: usize, ps_shift: usize, mmfr0: usize) -> usize {
+    let parange = mmfr0 & 0xf;
+    if parange < (value >> ps_shift) & 0b111 {
+        (value & !(0b111 << ps_shift)) | (parange << ps_shift)
+    } else {
+        value
+    }
+}
+
+/// `TCR_EL1` value `value` fitted to `mmfr0`: PS lowered to PARange and 8-bit ASIDs unless
+/// ASIDBits [7:4] is 0b0010.
+pub const fn tcr_el1_fit(value: usize, mmfr0: usize) -> usize {
+    let value = tcr_fit_ps(value, 32, mmfr0);
+    if (mmfr0 >> 4) & 0xf == 0b0010 {
+        value
+    } else {
+        value & !bit!(36)
+    }
+}
+
+/// Program the translation control register of the kernel, `TCR_EL1` or `TCR_EL2` with the
+/// `hypervisor` feature, fitted to the CPU. The caller has checked the granule, see
+/// `init_mmu_registers`.
+pub fn set_tcr() {
+    let mmfr0 = id_aa64mmfr0();
+    unsafe {
+        #[cfg(not(feature = "hypervisor"))]
+        core::arch::asm!("msr tcr_el1, {}", in(reg) tcr_el1_fit(TCR_EL1_VALUE, mmfr0));
+        #[cfg(feature = "hypervisor")]
+        core::arch::asm!("msr tcr_el2, {}", in(reg) tcr_fit_ps(TCR_EL2_VALUE, 16, mmfr0));
+    }
+    isb();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Shift of the level 0-3 entries, start level and root index bits of a 48-bit walk.
+    #[cfg(not(any(feature = "arm_granule_16k", feature = "arm_granule_64k")))]
+    const EXPECTED: ([usize; 4], usize, usize) = ([39, 30, 21, 12], 0, 9);
+    #[cfg(feature = "arm_granule_16k")]
+    const EXPECTED: ([usize; 4], usize, usize) = ([47, 36, 25, 14], 0, 1);
+    #[cfg(feature = "arm_granule_64k")]
+    const EXPECTED: ([usize; 4], usize, usize) = ([55, 42, 29, 16], 1, 6);
+
+    #[test]
+    fn level_shifts_follow_the_granule() {
+        for level in 0..4 {
+            assert_eq!(granule_level_shift(level), EXPECTED.0[level]);
+        }
+        assert_eq!(granule_level_shift(3), GRANULE_BITS);
+    }
+
+    #[test]
+    fn start_level_of_a_48_bit_walk() {
+        assert_eq!(granule_start_level(VA_BITS), EXPECTED.1);
+        assert_eq!(granule_root_index_bits(VA_BITS), EXPECTED.2);
+    }
+
+    #[test]
+    fn tcr_fits_the_cpu() {
+        // PARange as large as the kernel's, 16-bit ASIDs.
+        let mmfr0 = (0b0010 << 4) | TCR_PS;
+        assert_eq!(tcr_el1_fit(TCR_EL1_VALUE, mmfr0), TCR_EL1_VALUE);
+        // PARange 40 bits, 8-bit ASIDs.
+        let fitted = tcr_el1_fit(TCR_EL1_VALUE, 0b0010);
+        assert_eq!((fitted >> 32) & 0b111, 0b010);
+        assert_eq!(fitted & bit!(36), 0);
+        let fields = (0b111 << 32) | bit!(36);
+        assert_eq!(fitted & !fields, TCR_EL1_VALUE & !fields);
+    }
+
+    #[test]
+    fn root_table_covers_the_input_range() {
+        for va_bits in GRANULE_BITS + 1..=VA_BITS {
+            let level = granule_start_level(va_bits);
+            let root_bits = granule_root_index_bits(va_bits);
+            assert!((1..=GRANULE_INDEX_BITS).contains(&root_bits));
+            assert_eq!(granule_level_shift(level) + root_bits, va_bits);
+        }
+    }
+}
@@ -1,34 +1,54 @@
 use super::pte::pte_tag_t;
-use super::{kpptr_to_paddr, machine::*, UPT_LEVELS};
+use super::{
+    kpptr_to_paddr, machine::*, GRANULE_INDEX_BITS, KPT_ROOT_INDEX_BITS, KPT_START_LEVEL,
+    UPT_LEVELS,
+};
 use crate::arch::VAddr;
+use crate::kernel_image::KERNEL_IMAGE_BOUNDARIES;
 use crate::utils::PageAligned;
-use crate::{asid_t, find_vspace_for_asid, PTE};
+use crate::{
+    asid_t, check_user_frame, check_user_vaddr, find_vspace_for_asid, unmap_error_t, vaddr_error_t,
+    PageSize, PDE, PGDE, PTE, PUDE,
+};
 use core::intrinsics::unlikely;
 use rel4_arch::basic::{PAddr, PPtr, VPtr};
 use sel4_common::arch::MessageLabel;
 use sel4_common::structures::exception_t;
+use sel4_common::structures_gen::lookup_fault;
 use sel4_common::structures_gen::{cap, cap_tag, cap_vspace_cap};
-use sel4_common::utils::{pageBitsForSize, ptr_to_mut};
-use sel4_common::{sel4_config::SEL4_PAGE_BITS, structures_gen::lookup_fault};
+use sel4_common::utils::ptr_to_mut;
 use sel4_cspace::capability::cap_arch_func;
 
 #[no_mangle]
 #[link_section = ".page_table"]
-pub(crate) static mut armKSGlobalKernelPGD: PageAligned<PTE> = PageAligned::new(PTE(0));
+pub(crate) static mut armKSGlobalKernelPGD: PageAligned<PGDE> = PageAligned::new(PGDE(0));
 
 #[no_mangle]
 #[link_section = ".page_table"]
-pub(crate) static mut armKSGlobalKernelPUD: PageAligned<PTE> = PageAligned::new(PTE(0));
+pub(crate) static mut armKSGlobalKernelPUD: PageAligned<PUDE> = PageAligned::new(PUDE(0));
+
+/// One PD per entry of the kernel PUD, which only has the root index bits when it is the root.
+const KERNEL_PD_COUNT: usize = if KPT_START_LEVEL == 0 {
+    bit!(GRANULE_INDEX_BITS)
+} else {
+    bit!(KPT_ROOT_INDEX_BITS)
+};
 
 #[no_mangle]
 #[link_section = ".page_table"]
-pub(crate) static mut armKSGlobalKernelPDs: PageAligned<PageAligned<PTE>> =
-    PageAligned::new(PageAligned::new(PTE(0)));
+pub(crate) static mut armKSGlobalKernelPDs: [PageAligned<PDE>; KERNEL_PD_COUNT] =
+    [PageAligned::new(PDE(0)); KERNEL_PD_COUNT];
 
 #[no_mangle]
 #[link_section = ".page_table"]
 pub(crate) static mut armKSGlobalKernelPT: PageAligned<PTE> = PageAligned::new(PTE(0));
 
+/// Page tables for the 2 MiB regions of the kernel image which cross a section boundary.
+#[no_mangle]
+#[link_section = ".page_table"]
+pub(crate) static mut armKSGlobalKernelImagePTs: [PageAligned<PTE>; KERNEL_IMAGE_BOUNDARIES] =
+    [PageAligned::new(PTE(0)); KERNEL_IMAGE_BOUNDARIES];
+
 #[no_mangle]
 #[link_section = ".page_table"]
 pub(crate) static mut armKSGlobalUserVSpace: PageAligned<PTE> = PageAligned::new(PTE(0));
@@ -38,8 +58,18 @@ pub fn get_kernel_page_global_directory_base() -> usize {
     &raw const armKSGlobalKernelPGD as usize
 }
 
+/// Root of the kernel vspace, the PUD when there is no level 0 (64 KiB granule).
+#[inline]
+pub fn get_kernel_vspace_root_base() -> usize {
+    if KPT_START_LEVEL == 0 {
+        get_kernel_page_global_directory_base()
+    } else {
+        get_kernel_page_upper_directory_base()
+    }
+}
+
 #[inline]
-pub fn set_kernel_page_global_directory_by_index(idx: usize, pgde: PTE) {
+pub fn set_kernel_page_global_directory_by_index(idx: usize, pgde: PGDE) {
     unsafe { armKSGlobalKernelPGD[idx] = pgde }
 }
 
@@ -49,7 +79,7 @@ pub fn get_kernel_page_upper_directory_base() -> usize {
 }
 
 #[inline]
-pub fn set_kernel_page_upper_directory_by_index(idx: usize, pude: PTE) {
+pub fn set_kernel_page_upper_directory_by_index(idx: usize, pude: PUDE) {
     unsafe { armKSGlobalKernelPUD[idx] = pude }
 }
 
@@ -59,10 +89,25 @@ pub fn get_kernel_page_directory_base_by_index(idx: usize) -> usize {
 }
 
 #[inline]
-pub fn set_kernel_page_directory_by_index(idx1: usize, idx2: usize, pde: PTE) {
+pub fn get_kernel_page_directory_by_index(idx1: usize, idx2: usize) -> PDE {
+    unsafe { armKSGlobalKernelPDs[idx1][idx2] }
+}
+
+#[inline]
+pub fn set_kernel_page_directory_by_index(idx1: usize, idx2: usize, pde: PDE) {
     unsafe { armKSGlobalKernelPDs[idx1][idx2] = pde }
 }
 
+#[inline]
+pub fn get_kernel_image_page_table_base_by_index(idx: usize) -> usize {
+    unsafe { armKSGlobalKernelImagePTs[idx].as_ptr() as usize }
+}
+
+#[inline]
+pub fn set_kernel_image_page_table_by_index(idx1: usize, idx2: usize, pte: PTE) {
+    unsafe { armKSGlobalKernelImagePTs[idx1][idx2] = pte }
+}
+
 #[inline]
 pub fn get_arm_global_user_vspace_base() -> usize {
     &raw const armKSGlobalUserVSpace as usize
@@ -103,22 +148,88 @@ pub fn set_vm_root(thread_root: &cap) -> Result<(), lookup_fault> {
             return Ok(());
         }
     }
+    #[cfg(not(feature = "hypervisor"))]
     set_current_user_vspace_root(
         pptr!(thread_root_vspace.get_capVSBasePtr())
             .to_paddr()
             .raw(),
     );
+    #[cfg(feature = "hypervisor")]
+    set_current_user_vspace_root(ttbr_new(
+        super::get_hw_vmid(asid),
+        pptr!(thread_root_vspace.get_capVSBasePtr()).to_paddr(),
+    ));
     Ok(())
 }
 
+/// Why `init_mmu_registers` left the MMU registers unchanged.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum mmu_init_error_t {
+    /// The MMU is already enabled, its registers cannot change under live translations.
+    MmuEnabled,
+    /// The CPU does not implement the translation granule the kernel is built for.
+    GranuleUnsupported,
+    /// The CPU does not implement the FEAT_LPA2 descriptors the kernel is built for.
+    Lpa2Unsupported,
+}
+
+/// Whether the MMU of the kernel's exception level is enabled, `SCTLR_ELx.M`.
+#[inline]
+fn mmu_enabled() -> bool {
+    let sctlr: usize;
+    unsafe {
+        #[cfg(not(feature = "hypervisor"))]
+        core::arch::asm!("mrs {}, sctlr_el1", out(reg) sctlr);
+        #[cfg(feature = "hypervisor")]
+        core::arch::asm!("mrs {}, sctlr_el2", out(reg) sctlr);
+    }
+    sctlr & 1 != 0
+}
+
+/// Program MAIR, TCR and, with the `hypervisor` feature, VTCR for the kernel tables, and turn
+/// on the hardware access flag updates with `access_tracking`. The boot code of every core
+/// calls it before enabling the MMU, the TLB is invalidated so that no entry made with other
+/// values is used.
+///
+/// The output size and the ASID size are lowered to what the CPU implements. A granule or
+/// descriptor format it does not implement cannot be worked around, the registers are left
+/// unchanged and the error returned.
+#[link_section = ".boot.text"]
+pub fn init_mmu_registers() -> Result<(), mmu_init_error_t> {
+    if mmu_enabled() {
+        return Err(mmu_init_error_t::MmuEnabled);
+    }
+    if !super::granule_supported() {
+        return Err(mmu_init_error_t::GranuleUnsupported);
+    }
+    if super::PA_LPA2 && !super::lpa2_supported() {
+        return Err(mmu_init_error_t::Lpa2Unsupported);
+    }
+    set_mair();
+    super::set_tcr();
+    #[cfg(feature = "hypervisor")]
+    super::set_vtcr();
+    #[cfg(feature = "access_tracking")]
+    crate::access::set_hw_access_dirty(enable_hw_access_dirty());
+    invalidate_local_kernel_tlb();
+    #[cfg(feature = "hypervisor")]
+    unsafe {
+        core::arch::asm!("tlbi alle1");
+    }
+    dsb();
+    isb();
+    Ok(())
+}
+
+/// Switch to the kernel tables. MAIR and TCR are programmed by `init_mmu_registers` before the
+/// MMU is enabled, they are not changed here under live translations.
 #[no_mangle]
 #[link_section = ".boot.text"]
 pub fn activate_kernel_vspace() {
     clean_invalidate_l1_caches();
-    set_current_kernel_vspace_root(ttbr_new(
-        0,
-        kpptr_to_paddr(get_kernel_page_global_directory_base()),
-    ));
+    #[cfg(feature = "hypervisor")]
+    super::vmid_init();
+    set_current_kernel_vspace_root(ttbr_new(0, kpptr_to_paddr(get_kernel_vspace_root_base())));
     set_current_user_vspace_root(ttbr_new(
         0,
         kpptr_to_paddr(get_arm_global_user_vspace_base()),
@@ -141,12 +252,21 @@ pub fn set_vm_root_for_flush_with_thread_root(
     }
 
     // armv_context_switch(vspace, asid);
+    #[cfg(not(feature = "hypervisor"))]
     set_current_user_vspace_root(ttbr_new(asid, paddr!(vspace)));
+    #[cfg(feature = "hypervisor")]
+    set_current_user_vspace_root(ttbr_new(super::get_hw_vmid(asid), paddr!(vspace)));
     true
 }
 
 #[inline]
 pub fn invalidate_tlb_by_asid(asid: asid_t) {
+    // Guest entries are tagged by VMID, a vspace without one has nothing in the TLB.
+    #[cfg(feature = "hypervisor")]
+    if let Some(vmid) = super::find_hw_vmid(asid) {
+        invalidate_local_tlb_vmid(vmid);
+    }
+    #[cfg(not(feature = "hypervisor"))]
     invalidate_local_tlb_asid(asid);
     #[cfg(feature = "enable_smp")]
     {
@@ -161,22 +281,40 @@ pub fn invalidate_tlb_by_asid(asid: asid_t) {
 
 #[inline]
 pub fn invalidate_tlb_by_asid_va(asid: asid_t, vaddr: VPtr) {
-    invalidate_local_tlb_va_asid((asid << 48) | vaddr.raw() >> SEL4_PAGE_BITS);
+    #[cfg(feature = "hypervisor")]
+    if let Some(vmid) = super::find_hw_vmid(asid) {
+        invalidate_local_tlb_ipa_vmid(vmid, vaddr.raw());
+    }
+    #[cfg(not(feature = "hypervisor"))]
+    invalidate_local_tlb_va_asid((asid << 48) | vaddr.raw() >> TLBI_ADDR_SHIFT);
     #[cfg(feature = "enable_smp")]
     {
         extern "C" {
             fn remote_invalidate_translation_single(vptr: usize);
         }
         unsafe {
-            remote_invalidate_translation_single((asid << 48) | vaddr.raw() >> SEL4_PAGE_BITS);
+            remote_invalidate_translation_single((asid << 48) | vaddr.raw() >> TLBI_ADDR_SHIFT);
         }
     }
 }
 
-pub fn unmap_page_table(asid: asid_t, vaddr: VPtr, pt: &PTE) {
+/// Invalidate the entries of `asid` for the `count` leaves of `size_bits` from `vaddr` on all
+/// cores, with the inner shareable TLBI operations.
+#[inline]
+pub fn invalidate_tlb_by_asid_range(asid: asid_t, vaddr: VPtr, count: usize, size_bits: usize) {
+    #[cfg(feature = "hypervisor")]
+    if let Some(vmid) = super::find_hw_vmid(asid) {
+        invalidate_tlb_ipa_range_vmid_is(vmid, vaddr.raw(), count, size_bits);
+    }
+    #[cfg(not(feature = "hypervisor"))]
+    invalidate_tlb_va_range_asid_is(asid, vaddr.raw(), count, size_bits);
+}
+
+pub fn unmap_page_table(asid: asid_t, vaddr: VPtr, pt: &PTE) -> Result<(), vaddr_error_t> {
+    check_user_vaddr(vaddr)?;
     let find_ret = find_vspace_for_asid(asid);
     if find_ret.status != exception_t::EXCEPTION_NONE {
-        return;
+        return Ok(());
     }
     let mut ptSlot: *mut PTE = core::ptr::null_mut::<PTE>();
     let mut pte = find_ret.vspace_root.unwrap();
@@ -184,7 +322,7 @@ pub fn unmap_page_table(asid: asid_t, vaddr: VPtr, pt: &PTE) {
     while level < UPT_LEVELS - 1 && pte as usize != pt as *const PTE as usize {
         ptSlot = unsafe { pte.add(VAddr(vaddr.raw()).get_upt_index(level)) };
         if ptr_to_mut(ptSlot).get_type() != (pte_tag_t::pte_table) as usize {
-            return;
+            return Ok(());
         }
         pte = ptr_to_mut(ptSlot)
             .next_level_paddr()
@@ -193,14 +331,13 @@ pub fn unmap_page_table(asid: asid_t, vaddr: VPtr, pt: &PTE) {
         level = level + 1;
     }
     if pte as usize != pt as *const PTE as usize {
-        return;
+        return Ok(());
     }
     assert!(!ptSlot.is_null());
-    unsafe {
-        *(ptSlot) = PTE(0);
-        ptr_to_mut(ptSlot).update(*(pte));
-    }
+    // `update` splits a contiguous group before the entry is cleared.
+    ptr_to_mut(ptSlot).update(PTE(0));
     invalidate_tlb_by_asid(asid);
+    Ok(())
 }
 
 /// Unmap a page table
@@ -210,14 +347,19 @@ pub fn unmap_page(
     asid: asid_t,
     vptr: VPtr,
     pptr: PPtr,
-) -> Result<(), lookup_fault> {
+) -> Result<(), unmap_error_t> {
     let addr = pptr.to_paddr();
     let find_ret = find_vspace_for_asid(asid);
     if unlikely(find_ret.status != exception_t::EXCEPTION_NONE) {
         return Ok(());
     }
+    let page_size = match PageSize::from_cap_size(page_size) {
+        Some(page_size) => page_size,
+        None => return Ok(()),
+    };
+    check_user_frame(vptr, page_size)?;
     let lu_ret = PTE::new_from_pte(find_ret.vspace_root.unwrap() as usize).lookup_pt_slot(vptr);
-    if unlikely(lu_ret.ptBitsLeft != pageBitsForSize(page_size)) {
+    if unlikely(lu_ret.ptBitsLeft != page_size.bits()) {
         return Ok(());
     }
 
@@ -230,12 +372,11 @@ pub fn unmap_page(
     if pte.get_page_base_address() != addr {
         return Ok(());
     }
-    unsafe {
-        *(lu_ret.ptSlot) = PTE(0);
-        pte.update(*(lu_ret.ptSlot));
-    }
-    assert!(asid < bit!(16));
+    pte.split_group(asid, vptr, page_size.bits());
+    pte.update(PTE(0));
     invalidate_tlb_by_asid(asid);
+    #[cfg(feature = "reverse_map")]
+    crate::rmap_remove(addr, asid, vptr);
     Ok(())
 
     // match page_size {
@@ -26,25 +26,18 @@ pub fn set_current_user_vspace_root(val: usize) {
         registers::TTBR0_EL1.set(val as _);
         unsafe { core::arch::asm!("tlbi vmalle1") };
     }
+    // Guest vspaces are tagged with a VMID, the TLB entries of other guests stay valid.
     #[cfg(feature = "hypervisor")]
     {
         registers::VTTBR_EL2.set(val as _);
-        invalidate_local_tlb();
-        unsafe {
-            asm!("tlbi alle2");
-            dsb();
-            asm!("tlbi alle1");
-        }
     }
     dsb();
     isb();
-    // log::warn!("virtual ttbr el2: {:#x}", val);
-    // FIXME: use aisd instead of flush tlb
 }
 
 #[inline]
 pub const fn ttbr_new(asid: usize, addr: PAddr) -> usize {
-    (asid & 0xffff) << 48 | (addr.raw() & 0xffffffffffff)
+    (asid & 0xffff) << 48 | super::pa::ttbr_baddr(addr.raw())
 }
 
 /**
@@ -80,6 +73,57 @@ pub fn invalidate_local_tlb_asid(asid: usize) {
     isb();
 }
 
+/// Invalidate the stage 1 and stage 2 TLB entries of a guest.
+///
+/// `tlbi vmalls12e1` acts on the VMID in `VTTBR_EL2`, so switch to `vmid` while doing it.
+#[cfg(feature = "hypervisor")]
+#[inline]
+pub fn invalidate_local_tlb_vmid(vmid: usize) {
+    let vttbr: usize;
+    unsafe {
+        asm!("mrs {}, vttbr_el2", out(reg) vttbr);
+        asm!("msr vttbr_el2, {}", in(reg) ((vmid & mask_bits!(super::VMID_BITS)) << 48));
+    }
+    isb();
+    dsb();
+    unsafe {
+        asm!("tlbi vmalls12e1");
+    }
+    dsb();
+    unsafe {
+        asm!("msr vttbr_el2, {}", in(reg) vttbr);
+    }
+    isb();
+}
+
+/// TLBI address operands hold the address in 4 KiB units whatever the granule.
+pub const TLBI_ADDR_SHIFT: usize = 12;
+
+/// Invalidate the stage 2 entries of one IPA of a guest, and its combined stage 1 entries.
+#[cfg(feature = "hypervisor")]
+#[inline]
+pub fn invalidate_local_tlb_ipa_vmid(vmid: usize, ipa: usize) {
+    let vttbr: usize;
+    unsafe {
+        asm!("mrs {}, vttbr_el2", out(reg) vttbr);
+        asm!("msr vttbr_el2, {}", in(reg) ((vmid & mask_bits!(super::VMID_BITS)) << 48));
+    }
+    isb();
+    dsb();
+    unsafe {
+        asm!("tlbi ipas2e1, {}", in(reg) (ipa >> TLBI_ADDR_SHIFT));
+    }
+    dsb();
+    unsafe {
+        asm!("tlbi vmalle1");
+    }
+    dsb();
+    unsafe {
+        asm!("msr vttbr_el2, {}", in(reg) vttbr);
+    }
+    isb();
+}
+
 #[inline]
 pub fn invalidate_local_tlb_va_asid(mva_plus_asid: usize) {
     dsb();
@@ -90,6 +134,50 @@ pub fn invalidate_local_tlb_va_asid(mva_plus_asid: usize) {
     isb();
 }
 
+/// Invalidate the entries of `asid` for the `count` leaves of `size_bits` from `vaddr`, on every
+/// core of the inner shareable domain.
+#[inline]
+pub fn invalidate_tlb_va_range_asid_is(asid: usize, vaddr: usize, count: usize, size_bits: usize) {
+    barrier::dsb(barrier::ISHST);
+    for i in 0..count {
+        let va = vaddr + (i << size_bits);
+        unsafe {
+            asm!("tlbi vae1is, {}", in(reg) (asid << 48) | va >> TLBI_ADDR_SHIFT);
+        }
+    }
+    barrier::dsb(barrier::ISH);
+    isb();
+}
+
+/// Invalidate the stage 2 entries of a guest for the `count` leaves of `size_bits` from `ipa`,
+/// and its combined stage 1 entries, on every core of the inner shareable domain.
+#[cfg(feature = "hypervisor")]
+#[inline]
+pub fn invalidate_tlb_ipa_range_vmid_is(vmid: usize, ipa: usize, count: usize, size_bits: usize) {
+    let vttbr: usize;
+    unsafe {
+        asm!("mrs {}, vttbr_el2", out(reg) vttbr);
+        asm!("msr vttbr_el2, {}", in(reg) ((vmid & mask_bits!(super::VMID_BITS)) << 48));
+    }
+    isb();
+    barrier::dsb(barrier::ISHST);
+    for i in 0..count {
+        let ipa = ipa + (i << size_bits);
+        unsafe {
+            asm!("tlbi ipas2e1is, {}", in(reg) (ipa >> TLBI_ADDR_SHIFT));
+        }
+    }
+    barrier::dsb(barrier::ISH);
+    unsafe {
+        asm!("tlbi vmalle1is");
+    }
+    barrier::dsb(barrier::ISH);
+    unsafe {
+        asm!("msr vttbr_el2, {}", in(reg) vttbr);
+    }
+    isb();
+}
+
 #[inline(always)]
 pub fn clean_by_va_pou(vaddr: usize, _paddr: PAddr) {
     unsafe {
@@ -336,6 +424,22 @@ pub fn invalidate_local_tlb() {
     isb();
 }
 
+/// Invalidate the kernel's own translations on the local core.
+#[inline]
+pub fn invalidate_local_kernel_tlb() {
+    dsb();
+    #[cfg(not(feature = "hypervisor"))]
+    unsafe {
+        asm!("tlbi vmalle1");
+    }
+    #[cfg(feature = "hypervisor")]
+    unsafe {
+        asm!("tlbi alle2");
+    }
+    dsb();
+    isb();
+}
+
 /*
  * Memory types are defined in Memory Attribute Indirection Register.
  *  - nGnRnE Device non-Gathering, non-Reordering, No Early write acknowledgement
@@ -344,8 +448,9 @@ pub fn invalidate_local_tlb() {
  *  - NORMAL_NC Normal Memory, Inner/Outer non-cacheable
  *  - NORMAL Normal Memory, Inner/Outer Write-back non-transient, Write-allocate, Read-allocate
  *  - NORMAL_WT Normal Memory, Inner/Outer Write-through non-transient, No-Write-allocate, Read-allocate
- * Note: These should match with contents of MAIR_EL1 register!
+ * Note: These should match with contents of MAIR_EL1 register, see `MAIR_EL1_VALUE`!
  */
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum mair_types {
     DEVICE_nGnRnE,
     DEVICE_nGnRE,
@@ -354,3 +459,130 @@ pub enum mair_types {
     NORMAL,
     NORMAL_WT,
 }
+
+impl mair_types {
+    pub const ALL: [mair_types; 6] = [
+        mair_types::DEVICE_nGnRnE,
+        mair_types::DEVICE_nGnRE,
+        mair_types::DEVICE_GRE,
+        mair_types::NORMAL_NC,
+        mair_types::NORMAL,
+        mair_types::NORMAL_WT,
+    ];
+
+    /// Attr<n> field programmed into MAIR_EL1 for this type.
+    pub const fn mair_attr(&self) -> usize {
+        match self {
+            mair_types::DEVICE_nGnRnE => 0x00,
+            mair_types::DEVICE_nGnRE => 0x04,
+            mair_types::DEVICE_GRE => 0x0c,
+            mair_types::NORMAL_NC => 0x44,
+            mair_types::NORMAL => 0xff,
+            mair_types::NORMAL_WT => 0xaa,
+        }
+    }
+
+    pub const fn from_index(index: usize) -> Option<Self> {
+        if index < Self::ALL.len() {
+            Some(Self::ALL[index])
+        } else {
+            None
+        }
+    }
+
+    /// Normal memory types which are cached in the inner/outer caches.
+    pub const fn is_cacheable(&self) -> bool {
+        matches!(self, mair_types::NORMAL | mair_types::NORMAL_WT)
+    }
+}
+
+const fn mair_el1_value() -> usize {
+    let mut value = 0;
+    let mut i = 0;
+    while i < mair_types::ALL.len() {
+        value |= mair_types::ALL[i].mair_attr() << (i * 8);
+        i += 1;
+    }
+    value
+}
+
+/// MAIR_EL1 value with Attr<n> set from `mair_types`, index n being the enum discriminant.
+pub const MAIR_EL1_VALUE: usize = mair_el1_value();
+
+/// Program MAIR so that AttrIndx values in page tables match `mair_types`.
+#[inline]
+pub fn set_mair() {
+    #[cfg(not(feature = "hypervisor"))]
+    registers::MAIR_EL1.set(MAIR_EL1_VALUE as _);
+    #[cfg(feature = "hypervisor")]
+    registers::MAIR_EL2.set(MAIR_EL1_VALUE as _);
+    isb();
+}
+
+/// Enable hardware updates of the access flag and of the dirty state of DBM entries, as far as
+/// FEAT_HAFDBS is implemented. Returns whether the dirty state is updated by hardware.
+#[cfg(feature = "access_tracking")]
+pub fn enable_hw_access_dirty() -> bool {
+    let mmfr1: usize;
+    unsafe {
+        asm!("mrs {}, id_aa64mmfr1_el1", out(reg) mmfr1);
+    }
+    // ID_AA64MMFR1_EL1.HAFDBS: 1 updates the access flag, 2 also the dirty state.
+    let hafdbs = mmfr1 & 0xf;
+    if hafdbs == 0 {
+        return false;
+    }
+    let (ha, hd) = if cfg!(feature = "hypervisor") {
+        (bit!(21), bit!(22))
+    } else {
+        (bit!(39), bit!(40))
+    };
+    let bits = if hafdbs >= 2 { ha | hd } else { ha };
+    unsafe {
+        #[cfg(feature = "hypervisor")]
+        asm!(
+            "mrs {tmp}, vtcr_el2",
+            "orr {tmp}, {tmp}, {bits}",
+            "msr vtcr_el2, {tmp}",
+            tmp = out(reg) _,
+            bits = in(reg) bits,
+        );
+        #[cfg(not(feature = "hypervisor"))]
+        asm!(
+            "mrs {tmp}, tcr_el1",
+            "orr {tmp}, {tmp}, {bits}",
+            "msr tcr_el1, {tmp}",
+            tmp = out(reg) _,
+            bits = in(reg) bits,
+        );
+    }
+    isb();
+    hafdbs >= 2
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mair_value_matches_the_memory_types() {
+        assert_eq!(MAIR_EL1_VALUE, 0xaa_ff_44_0c_04_00);
+        for (index, memory_type) in mair_types::ALL.iter().enumerate() {
+            assert_eq!(*memory_type as usize, index);
+            assert_eq!(mair_types::from_index(index), Some(*memory_type));
+            assert_eq!(
+                (MAIR_EL1_VALUE >> (index * 8)) & 0xff,
+                memory_type.mair_attr()
+            );
+        }
+        assert_eq!(mair_types::from_index(mair_types::ALL.len()), None);
+    }
+
+    #[test]
+    fn normal_wt_is_write_through_no_write_allocate() {
+        // Outer and inner 0b1010: write-through non-transient, read-allocate, no write-allocate.
+        assert_eq!(mair_types::NORMAL_WT.mair_attr(), 0xaa);
+        assert!(mair_types::NORMAL_WT.is_cacheable());
+        assert!(!mair_types::NORMAL_NC.is_cacheable());
+    }
+}
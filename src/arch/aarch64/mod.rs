@@ -1,16 +1,35 @@
 mod asid;
 mod boot;
+mod contiguous;
 mod device;
+mod granule;
 mod interface;
 mod machine;
+#[cfg(feature = "hypervisor")]
+mod nested;
+mod pa;
 mod pte;
+#[cfg(feature = "hypervisor")]
+mod stage2;
 mod structures;
 mod utils;
+#[cfg(feature = "hypervisor")]
+mod vmid;
 pub use asid::*;
 pub use boot::*;
+pub use contiguous::*;
 pub use device::*;
+pub use granule::*;
 pub use interface::*;
 pub use machine::*;
-pub use pte::{pte_tag_t, PTEFlags};
+#[cfg(feature = "hypervisor")]
+pub use nested::*;
+pub use pa::*;
+pub(crate) use pte::lookup_vspace_slot;
+pub use pte::{pte_tag_t, PTEFlags, PTE_SW_BITS, PTE_SW_BITS_SHIFT};
+#[cfg(feature = "hypervisor")]
+pub use stage2::*;
 pub use structures::*;
 pub use utils::*;
+#[cfg(feature = "hypervisor")]
+pub use vmid::*;
@@ -0,0 +1,176 @@
+//! Descriptor decoding and table geometry used by the nested walker in `crate::nested`.
+use rel4_arch::basic::PAddr;
+
+use super::granule::{GRANULE_BITS, GRANULE_INDEX_BITS};
+use super::pa::PA_BITS;
+use super::utils::{
+    DESC_TYPE_BLOCK, DESC_TYPE_MASK, DESC_TYPE_PAGE, DESC_TYPE_TABLE, UPT_LEVELS, VSPACE_INDEX_BITS,
+};
+use crate::nested::{translation_stage_t, walk_entry_t, walk_geometry_t};
+use crate::PTE;
+
+/// Stage 2 tables are the user vspaces, starting at the level required by the IPA size
+/// with a possibly concatenated root.
+pub(crate) const S2_GEOMETRY: walk_geometry_t = walk_geometry_t {
+    levels: UPT_LEVELS,
+    granule_bits: GRANULE_BITS,
+    index_bits: GRANULE_INDEX_BITS,
+    root_index_bits: VSPACE_INDEX_BITS,
+    sign_extend: false,
+    output_bits: PA_BITS,
+};
+
+/// Largest input range of a guest stage 1 walk, without FEAT_LVA.
+const S1_MAX_INPUT_BITS: usize = 48;
+
+impl walk_geometry_t {
+    /// The geometry of the guest's TTBR0 tables described by the guest's `TCR_EL1`:
+    /// the granule from TG0, the input range from T0SZ and 52-bit output addresses from DS
+    /// (FEAT_LPA2) or from IPS with the 64 KiB granule (FEAT_LPA).
+    ///
+    /// `None` if the TTBR0 walks are disabled (EPD0) or the fields are reserved.
+    /// The root is at `TTBR0_EL1.BADDR`.
+    pub fn from_tcr_el1(tcr: usize) -> Option<Self> {
+        if tcr & bit!(7) != 0 {
+            return None;
+        }
+        let granule_bits = match (tcr >> 14) & 0b11 {
+            0b00 => 12,
+            0b01 => 16,
+            0b10 => 14,
+            _ => return None,
+        };
+        let index_bits = granule_bits - 3;
+        let input_bits = 64 - (tcr & 0x3f);
+        if input_bits > S1_MAX_INPUT_BITS || input_bits <= granule_bits {
+            return None;
+        }
+        let levels = (input_bits - granule_bits).div_ceil(index_bits);
+        let lpa2 = granule_bits != 16 && tcr & bit!(59) != 0;
+        let lpa = granule_bits == 16 && (tcr >> 32) & 0b111 == 0b110;
+        Some(Self {
+            levels,
+            granule_bits,
+            index_bits,
+            root_index_bits: input_bits - granule_bits - index_bits * (levels - 1),
+            sign_extend: false,
+            output_bits: if lpa || lpa2 { 52 } else { 48 },
+        })
+    }
+
+    /// The output address of the guest stage 1 descriptor `desc`, 52-bit ones are encoded
+    /// like the kernel's own (see `super::pa`).
+    #[inline]
+    const fn s1_output_address(&self, desc: usize) -> usize {
+        let granule_mask = mask_bits!(self.granule_bits);
+        if self.output_bits != 52 {
+            desc & mask_bits!(48) & !granule_mask
+        } else if self.granule_bits == 16 {
+            (desc & mask_bits!(48) & !granule_mask) | (((desc >> 12) & 0xf) << 48)
+        } else {
+            (desc & mask_bits!(50) & !granule_mask) | (((desc >> 8) & 0x3) << 50)
+        }
+    }
+
+    /// ARM level of the tables at `level` of the walk, the last one is level 3.
+    #[inline]
+    const fn arm_level(&self, level: usize) -> usize {
+        4 - self.levels + level
+    }
+}
+
+impl PTE {
+    /// Decode a descriptor found at `level` of a walk with the given geometry.
+    ///
+    /// Stage 1 and stage 2 descriptors share the type encoding in bits[1:0], the output address
+    /// of stage 2 ones is encoded like the kernel's own descriptors.
+    /// Blocks are only allowed from level 1 with the 4 KiB granule, and at level 2 otherwise.
+    pub(crate) fn walk_entry(
+        &self,
+        stage: translation_stage_t,
+        geometry: &walk_geometry_t,
+        level: usize,
+    ) -> walk_entry_t {
+        let base: PAddr = match stage {
+            translation_stage_t::Stage1 => paddr!(geometry.s1_output_address(self.0)),
+            translation_stage_t::Stage2 => self.get_page_base_address(),
+        };
+        let last = level == geometry.levels - 1;
+        let min_block_level = if geometry.granule_bits == 12 { 1 } else { 2 };
+        match self.0 & DESC_TYPE_MASK {
+            DESC_TYPE_PAGE if last => walk_entry_t::Leaf(base),
+            DESC_TYPE_TABLE if !last => walk_entry_t::Table(base),
+            DESC_TYPE_BLOCK if !last && geometry.arm_level(level) >= min_block_level => {
+                walk_entry_t::Leaf(base)
+            }
+            _ => walk_entry_t::Invalid,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `TCR_EL1` with the TG0 encoding `tg0` and `T0SZ = 64 - input_bits`.
+    const fn tcr(tg0: usize, input_bits: usize) -> usize {
+        (tg0 << 14) | (64 - input_bits)
+    }
+
+    #[test]
+    fn geometry_from_tcr_el1() {
+        // (TG0, input bits) -> (granule bits, levels, root index bits)
+        let cases = [
+            ((0b00, 48), (12, 4, 9)),
+            ((0b00, 39), (12, 3, 9)),
+            ((0b00, 32), (12, 3, 2)),
+            ((0b10, 48), (14, 4, 1)),
+            ((0b10, 47), (14, 3, 11)),
+            ((0b01, 48), (16, 3, 6)),
+            ((0b01, 42), (16, 2, 13)),
+        ];
+        for ((tg0, input_bits), (granule_bits, levels, root_index_bits)) in cases {
+            let geometry = walk_geometry_t::from_tcr_el1(tcr(tg0, input_bits)).unwrap();
+            assert_eq!(geometry.granule_bits, granule_bits);
+            assert_eq!(geometry.index_bits, granule_bits - 3);
+            assert_eq!(geometry.levels, levels);
+            assert_eq!(geometry.root_index_bits, root_index_bits);
+            assert_eq!(geometry.input_bits(), input_bits);
+            assert_eq!(geometry.arm_level(levels - 1), 3);
+            assert!(geometry.contains(mask_bits!(input_bits)));
+            assert!(!geometry.contains(bit!(input_bits)));
+        }
+    }
+
+    #[test]
+    fn reserved_or_disabled_tcr_el1() {
+        // EPD0
+        assert_eq!(walk_geometry_t::from_tcr_el1(tcr(0b00, 48) | bit!(7)), None);
+        // Reserved TG0
+        assert_eq!(walk_geometry_t::from_tcr_el1(tcr(0b11, 48)), None);
+        // Beyond 48 bits without FEAT_LVA
+        assert_eq!(walk_geometry_t::from_tcr_el1(tcr(0b00, 52)), None);
+    }
+
+    #[test]
+    fn s1_output_addresses() {
+        let pa = bit!(51) | bit!(49) | 0x1234_0000;
+        // 48-bit output, the upper bits of the descriptor are attributes.
+        let geometry = walk_geometry_t::from_tcr_el1(tcr(0b00, 48)).unwrap();
+        assert_eq!(geometry.output_bits, 48);
+        assert_eq!(
+            geometry.s1_output_address(bit!(54) | 0x1234_0003),
+            0x1234_0000
+        );
+        // FEAT_LPA2: PA[51:50] in bits[9:8].
+        let geometry = walk_geometry_t::from_tcr_el1(tcr(0b00, 48) | bit!(59)).unwrap();
+        assert_eq!(geometry.output_bits, 52);
+        let desc = (pa & mask_bits!(50)) | (0b10 << 8) | 0x3;
+        assert_eq!(geometry.s1_output_address(desc), pa);
+        // FEAT_LPA: PA[51:48] in bits[15:12] with the 64 KiB granule and a 52-bit IPS.
+        let geometry = walk_geometry_t::from_tcr_el1(tcr(0b01, 48) | (0b110 << 32)).unwrap();
+        assert_eq!(geometry.output_bits, 52);
+        let desc = (pa & mask_bits!(48)) | (0b1000 << 12) | 0x3;
+        assert_eq!(geometry.s1_output_address(desc), pa);
+    }
+}
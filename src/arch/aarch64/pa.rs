@@ -0,0 +1,139 @@
+//! Output addresses of descriptors and translation table base registers, 48-bit by default or
+//! 52-bit with the `arm_pa_52bit` feature.
+//!
+//! 52-bit addresses do not fit the 48-bit address field of a descriptor:
+//! - with the 64 KiB granule (FEAT_LPA) PA[51:48] are held in bits[15:12],
+//! - with the 4 KiB and 16 KiB granules (FEAT_LPA2) the field is extended to PA[49:12] and
+//!   PA[51:50] are held in bits[9:8], which replace SH. The shareability then comes from the
+//!   SH fields of `TCR_ELx` / `VTCR_EL2`, which `DS` selects.
+//!
+//! In both cases the base registers hold PA[51:48] of the table in bits[5:2].
+use super::granule::GRANULE_BITS;
+
+/// Size of the physical address space.
+#[cfg(not(feature = "arm_pa_52bit"))]
+pub const PA_BITS: usize = 48;
+#[cfg(feature = "arm_pa_52bit")]
+pub const PA_BITS: usize = 52;
+
+/// Whether descriptors use the FEAT_LPA2 encoding, losing their SH field.
+pub const PA_LPA2: bool = PA_BITS == 52 && GRANULE_BITS != 16;
+
+/// Descriptor bits holding the output address.
+pub const DESC_ADDR_MASK: usize = if PA_BITS == 48 {
+    mask_bits!(48) & !mask_bits!(GRANULE_BITS)
+} else if PA_LPA2 {
+    (mask_bits!(50) & !mask_bits!(GRANULE_BITS)) | (0x3 << 8)
+} else {
+    mask_bits!(48) & !mask_bits!(12)
+};
+
+/// PS / IPS field of `TCR_ELx` and `VTCR_EL2`.
+pub const TCR_PS: usize = if PA_BITS == 52 { 0b110 } else { 0b101 };
+
+/// Place the granule aligned address `pa` in the address field of a descriptor.
+#[inline]
+pub const fn desc_addr_encode(pa: usize) -> usize {
+    if PA_BITS == 48 {
+        pa & DESC_ADDR_MASK
+    } else if PA_LPA2 {
+        (pa & mask_bits!(50) & !mask_bits!(GRANULE_BITS)) | (((pa >> 50) & 0x3) << 8)
+    } else {
+        (pa & mask_bits!(48) & !mask_bits!(16)) | (((pa >> 48) & 0xf) << 12)
+    }
+}
+
+/// Get the output address held by the descriptor `desc`.
+#[inline]
+pub const fn desc_addr_decode(desc: usize) -> usize {
+    if PA_BITS == 48 {
+        desc & DESC_ADDR_MASK
+    } else if PA_LPA2 {
+        (desc & mask_bits!(50) & !mask_bits!(GRANULE_BITS)) | (((desc >> 8) & 0x3) << 50)
+    } else {
+        (desc & mask_bits!(48) & !mask_bits!(16)) | (((desc >> 12) & 0xf) << 48)
+    }
+}
+
+/// SH field of a leaf descriptor, which does not exist with FEAT_LPA2.
+#[inline]
+pub const fn desc_sh(sh: usize) -> usize {
+    if PA_LPA2 {
+        0
+    } else {
+        (sh & 0x3) << 8
+    }
+}
+
+/// BADDR field of `TTBRn_ELx` / `VTTBR_EL2` for the table at `pa`.
+#[inline]
+pub const fn ttbr_baddr(pa: usize) -> usize {
+    if PA_BITS == 48 {
+        pa & mask_bits!(48)
+    } else {
+        (pa & mask_bits!(48) & !mask_bits!(6)) | (((pa >> 48) & 0xf) << 2)
+    }
+}
+
+/// Whether the CPU implements the FEAT_LPA2 descriptors of the selected granule, which the
+/// tables use with `PA_LPA2`. The 64 KiB granule has no such descriptors.
+pub fn lpa2_supported() -> bool {
+    let mmfr0 = super::id_aa64mmfr0();
+    // LPA2 shows in TGran4 [31:28] and TGran16 [23:20].
+    match GRANULE_BITS {
+        12 => (mmfr0 >> 28) & 0xf == 0b0001,
+        14 => (mmfr0 >> 20) & 0xf == 0b0010,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Granule aligned addresses spread over the whole physical address space.
+    const ADDRS: [usize; 5] = [
+        0,
+        bit!(GRANULE_BITS),
+        0x1234_5678_0000,
+        mask_bits!(PA_BITS) & !mask_bits!(GRANULE_BITS),
+        bit!(PA_BITS - 1) | bit!(GRANULE_BITS),
+    ];
+
+    #[test]
+    fn desc_addr_round_trips() {
+        for pa in ADDRS {
+            let desc = desc_addr_encode(pa);
+            assert_eq!(desc & !DESC_ADDR_MASK, 0, "{:#x}", pa);
+            assert_eq!(desc_addr_decode(desc), pa, "{:#x}", pa);
+            // The attribute bits around the address field are not part of the address.
+            assert_eq!(desc_addr_decode(desc | !DESC_ADDR_MASK), pa, "{:#x}", pa);
+        }
+    }
+
+    #[test]
+    fn desc_addr_fields() {
+        if PA_BITS == 48 {
+            assert_eq!(DESC_ADDR_MASK, mask_bits!(48) & !mask_bits!(GRANULE_BITS));
+        } else if PA_LPA2 {
+            // PA[51:50] replace SH in bits[9:8].
+            assert_eq!(desc_addr_encode(bit!(51) | bit!(50)), 0x3 << 8);
+            assert_eq!(desc_sh(0x3), 0);
+        } else {
+            // PA[51:48] are held in bits[15:12].
+            assert_eq!(desc_addr_encode(0xf << 48), 0xf << 12);
+        }
+        if !PA_LPA2 {
+            assert_eq!(desc_sh(0x3), 0x3 << 8);
+        }
+    }
+
+    #[test]
+    fn ttbr_baddr_holds_the_table_address() {
+        assert_eq!(ttbr_baddr(0x1234_5678_0000), 0x1234_5678_0000);
+        if PA_BITS == 52 {
+            // PA[51:48] of the table are held in bits[5:2].
+            assert_eq!(ttbr_baddr(bit!(51) | 0x1000), 0x1000 | bit!(5));
+        }
+    }
+}
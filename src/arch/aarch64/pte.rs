@@ -1,7 +1,13 @@
-use crate::{arch::aarch64::machine::clean_by_va_pou, vm_attributes_t, PTE};
+#[cfg(feature = "cow")]
+use crate::pte_sw_bit_t;
+use crate::{arch::aarch64::machine::clean_by_va_pou, vm_attributes_t, PageSize, PTE};
 
-use super::{mair_types, UPT_LEVELS, VSPACE_INDEX_BITS};
+use super::pa::{desc_addr_decode, desc_addr_encode, desc_sh};
+#[cfg(feature = "hypervisor")]
+use super::s2ap_t;
+use super::{PAGE_ADDR_MASK, UPT_LEVELS, VSPACE_INDEX_BITS};
 use crate::lookupPTSlot_ret_t;
+use core::sync::atomic::Ordering;
 use rel4_arch::basic::{PAddr, VPtr};
 use sel4_common::utils::ptr_to_mut;
 use sel4_common::{
@@ -10,25 +16,10 @@ use sel4_common::{
     utils::convert_ref_type_to_usize,
 };
 
-#[allow(unused)]
-pub enum VMPageSize {
-    ARMSmallPage = 0,
-    ARMLargePage,
-    ARMHugePage,
-}
-
-#[allow(unused)]
-impl VMPageSize {
-    /// Get VMPageSize from usize
-    pub fn try_from_usize(value: usize) -> Option<VMPageSize> {
-        match value {
-            0 => Some(VMPageSize::ARMSmallPage),
-            1 => Some(VMPageSize::ARMLargePage),
-            2 => Some(VMPageSize::ARMHugePage),
-            _ => None,
-        }
-    }
-}
+/// First software-reserved descriptor bit.
+pub const PTE_SW_BITS_SHIFT: usize = 55;
+/// Software-reserved bits 55-57, bit 58 holds the 4k page tag.
+pub const PTE_SW_BITS: usize = 3;
 
 #[allow(unused)]
 pub enum pte_tag_t {
@@ -59,6 +50,7 @@ bitflags::bitflags! {
         /// Access permission: read-only.
         const AP_RO =       bit!(7);
         /// Shareability: Inner Shareable (otherwise Outer Shareable).
+        /// With FEAT_LPA2 bits 8 and 9 hold PA[51:50] instead.
         const INNER =       bit!(8);
         /// Shareability: Inner or Outer Shareable (otherwise Non-shareable).
         const SHAREABLE =   bit!(9);
@@ -66,6 +58,8 @@ bitflags::bitflags! {
         const AF =          bit!(10);
         /// The not global bit.
         const NG =          bit!(11);
+        /// Dirty bit modifier: a read-only descriptor with DBM set is writable-clean.
+        const DBM =         bit!(51);
         /// Indicates that 16 adjacent translation table entries point to contiguous memory regions.
         const CONTIGUOUS =  bit!(52);
         /// The Privileged execute-never field.
@@ -92,7 +86,7 @@ bitflags::bitflags! {
 
 impl PTE {
     pub fn new(addr: PAddr, flags: PTEFlags) -> Self {
-        Self((addr.raw() & 0xfffffffff000) | flags.bits())
+        Self(desc_addr_encode(addr.raw()) | flags.bits())
     }
     pub fn pte_next_table(addr: PAddr, _: bool) -> Self {
         Self::new(addr, PTEFlags::VALID | PTEFlags::NON_BLOCK)
@@ -102,7 +96,7 @@ impl PTE {
     // }
 
     pub fn get_page_base_address(&self) -> PAddr {
-        paddr!(self.0 & 0xfffffffff000)
+        paddr!(desc_addr_decode(self.0))
     }
 
     pub fn get_pte_from_ppn_mut(&self) -> &mut PTE {
@@ -112,7 +106,7 @@ impl PTE {
     }
 
     pub fn get_ppn(&self) -> usize {
-        (self.0 & 0xfffffffff000) >> 10
+        (self.0 & PAGE_ADDR_MASK) >> 10
     }
 
     #[inline]
@@ -120,20 +114,32 @@ impl PTE {
         self.get_type() == (pte_tag_t::pte_4k_page) as usize
             || self.get_type() == (pte_tag_t::pte_page) as usize
     }
+    /// Whether the entry maps a page or a block.
+    #[inline]
+    pub(crate) fn is_leaf(&self) -> bool {
+        self.pte_is_page_type()
+    }
     pub fn is_pte_table(&self) -> bool {
-        self.get_type() != pte_tag_t::pte_table as usize
+        self.get_type() == pte_tag_t::pte_table as usize
     }
     pub fn get_valid(&self) -> usize {
         (self.get_type() != pte_tag_t::pte_invalid as usize) as usize
     }
 
     pub fn pte_table_get_present(&self) -> bool {
-        self.get_type() != pte_tag_t::pte_table as usize
+        self.get_type() == pte_tag_t::pte_table as usize
     }
 
     #[inline]
     pub fn update(&mut self, pte: Self) {
-        *self = pte;
+        self.prepare_update();
+        self.atomic().store(pte.0, Ordering::Release);
+        self.sync_update();
+    }
+
+    /// Make a change of the entry visible to the table walker.
+    #[inline]
+    pub(crate) fn sync_update(&self) {
         clean_by_va_pou(
             convert_ref_type_to_usize(self),
             paddr!(convert_ref_type_to_usize(self)),
@@ -148,6 +154,8 @@ impl PTE {
         }
     }
 
+    /// A user leaf for `paddr`, to be mapped with `map_user_leaf`, which checks the address.
+    #[cfg(not(feature = "hypervisor"))]
     pub fn make_user_pte(
         paddr: PAddr,
         rights: vm_rights_t,
@@ -155,20 +163,23 @@ impl PTE {
         page_size: usize,
     ) -> Self {
         let nonexecutable = attr.get_arm_execute_never();
-        let cacheable = attr.get_arm_page_cachable();
-        let mut attrindx = mair_types::DEVICE_nGnRnE as usize;
-        if cacheable {
-            attrindx = mair_types::NORMAL as usize;
-        }
+        let attrindx = attr.get_attr_index() as usize;
         let nG: usize = 1;
-        let vm_right: usize = Self::ap_from_vm_rights_t(rights).bits() >> 6;
+        let mut vm_right: usize = Self::ap_from_vm_rights_t(rights).bits() >> 6;
         let shareable = if cfg!(feature = "enable_smp") { 3 } else { 0 };
-        if VMPageSize::ARMSmallPage as usize == page_size {
+        // With access tracking, entries start not accessed and writable ones start clean.
+        let af = !cfg!(feature = "access_tracking") as usize;
+        let mut dbm = PTEFlags::empty();
+        if cfg!(feature = "access_tracking") && matches!(rights, vm_rights_t::VMReadWrite) {
+            vm_right |= PTEFlags::AP_RO.bits() >> 6;
+            dbm = PTEFlags::DBM;
+        }
+        let pte = if PageSize::Small.to_cap_size() == page_size {
             PTE::pte_new_4k_page(
                 nonexecutable as usize,
                 paddr,
                 nG,
-                1,
+                af,
                 shareable,
                 vm_right,
                 attrindx,
@@ -178,16 +189,138 @@ impl PTE {
                 nonexecutable as usize,
                 paddr,
                 nG,
-                1,
+                af,
                 shareable,
                 vm_right,
                 attrindx,
             )
+        };
+        PTE(pte.0 | dbm.bits())
+    }
+
+    /// User vspaces are guest-physical under `hypervisor`, so their leaves are stage 2 descriptors,
+    /// to be mapped with `map_user_leaf` as well.
+    #[cfg(feature = "hypervisor")]
+    pub fn make_user_pte(
+        paddr: PAddr,
+        rights: vm_rights_t,
+        attr: vm_attributes_t,
+        page_size: usize,
+    ) -> Self {
+        let nonexecutable = attr.get_arm_execute_never();
+        let mut s2ap = s2ap_t::from_vm_rights(rights) as usize;
+        let memattr = attr.get_attr_index().stage2_mem_attr();
+        let shareable = if cfg!(feature = "enable_smp") { 3 } else { 0 };
+        // With access tracking, entries start not accessed and writable ones start clean.
+        let af = !cfg!(feature = "access_tracking") as usize;
+        let mut dbm = PTEFlags::empty();
+        if cfg!(feature = "access_tracking") && matches!(rights, vm_rights_t::VMReadWrite) {
+            s2ap = s2ap_t::ReadOnly as usize;
+            dbm = PTEFlags::DBM;
         }
+        let pte = if PageSize::Small.to_cap_size() == page_size {
+            PTE::pte_new_s2_4k_page(nonexecutable as usize, paddr, af, shareable, s2ap, memattr)
+        } else {
+            PTE::pte_new_s2_page(nonexecutable as usize, paddr, af, shareable, s2ap, memattr)
+        };
+        PTE(pte.0 | dbm.bits())
+    }
+
+    /// Whether a leaf gives write access: AP[2] means read-only at stage 1, S2AP[1] means
+    /// writable at stage 2.
+    #[inline]
+    fn leaf_is_writable(&self) -> bool {
+        (self.0 & PTEFlags::AP_RO.bits() == 0) != cfg!(feature = "hypervisor")
+    }
+
+    #[inline]
+    fn leaf_with_write(&self, writable: bool) -> Self {
+        if writable != cfg!(feature = "hypervisor") {
+            PTE(self.0 & !PTEFlags::AP_RO.bits())
+        } else {
+            PTE(self.0 | PTEFlags::AP_RO.bits())
+        }
+    }
+
+    /// Whether the leaf was accessed since its bits were last cleared.
+    #[cfg(feature = "access_tracking")]
+    pub(crate) fn is_accessed(&self) -> bool {
+        self.pte_is_page_type() && self.0 & PTEFlags::AF.bits() != 0
+    }
+
+    /// Whether the leaf was written since its bits were last cleared, a DBM leaf is dirty once
+    /// it is writable.
+    #[cfg(feature = "access_tracking")]
+    pub(crate) fn is_dirty(&self) -> bool {
+        self.pte_is_page_type() && self.0 & PTEFlags::DBM.bits() != 0 && self.leaf_is_writable()
+    }
+
+    /// The leaf with the access flag clear and, with DBM, clean again, `None` if unchanged.
+    #[cfg(feature = "access_tracking")]
+    pub(crate) fn accessed_dirty_cleared(&self) -> Option<Self> {
+        if !self.pte_is_page_type() {
+            return None;
+        }
+        let mut pte = PTE(self.0 & !PTEFlags::AF.bits());
+        if pte.0 & PTEFlags::DBM.bits() != 0 {
+            pte = pte.leaf_with_write(false);
+        }
+        (pte.0 != self.0).then_some(pte)
+    }
+
+    /// The leaf resolving an access flag fault, or a write fault on a clean DBM leaf, `None` if
+    /// the fault does not come from access tracking.
+    #[cfg(feature = "access_tracking")]
+    pub(crate) fn accessed_marked(&self, write: bool) -> Option<Self> {
+        if !self.pte_is_page_type() {
+            return None;
+        }
+        let mut pte = PTE(self.0 | PTEFlags::AF.bits());
+        if write && !pte.leaf_is_writable() {
+            if pte.0 & PTEFlags::DBM.bits() == 0 {
+                return None;
+            }
+            pte = pte.leaf_with_write(true);
+        }
+        (pte.0 != self.0).then_some(pte)
+    }
+
+    /// Whether the leaf is shared copy-on-write.
+    #[cfg(feature = "cow")]
+    pub(crate) fn is_cow(&self) -> bool {
+        self.pte_is_page_type() && self.get_sw_bit(pte_sw_bit_t::Cow)
+    }
+
+    /// The writable leaf made read-only copy-on-write, `None` if unchanged. DBM is dropped so
+    /// that the hardware cannot make it writable again.
+    #[cfg(feature = "cow")]
+    pub(crate) fn cow_protected(&self) -> Option<Self> {
+        let dbm = PTEFlags::DBM.bits();
+        if !self.pte_is_page_type() || (!self.leaf_is_writable() && self.0 & dbm == 0) {
+            return None;
+        }
+        Some(
+            PTE(self.0 & !dbm)
+                .with_sw_bit(pte_sw_bit_t::Cow, true)
+                .leaf_with_write(false),
+        )
+    }
+
+    /// The copy-on-write leaf remapped writable to its private copy at `frame`. With access
+    /// tracking it gets back the DBM dropped by `cow_protected`, like every writable user leaf.
+    #[cfg(feature = "cow")]
+    pub(crate) fn cow_broken(&self, frame: PAddr) -> Self {
+        let mut pte = (self.with_sw_bit(pte_sw_bit_t::Cow, false).0 & !PAGE_ADDR_MASK)
+            | desc_addr_encode(frame.raw())
+            | PTEFlags::AF.bits();
+        if cfg!(feature = "access_tracking") {
+            pte |= PTEFlags::DBM.bits();
+        }
+        PTE(pte).leaf_with_write(true)
     }
 
     pub fn pte_new_table(pt_base_address: PAddr) -> PTE {
-        let val = 0 | (pt_base_address.raw() & 0xfffffffff000) | (0x3);
+        let val = 0 | desc_addr_encode(pt_base_address.raw()) | (0x3);
         PTE(val)
     }
 
@@ -202,10 +335,10 @@ impl PTE {
     ) -> PTE {
         let val = 0
             | (UXN & 0x1) << 54
-            | (page_base_address.raw() & 0xfffffffff000) >> 0
+            | desc_addr_encode(page_base_address.raw())
             | (nG & 0x1) << 11
             | (AF & 0x1) << 10
-            | (SH & 0x3) << 8
+            | desc_sh(SH)
             | (AP & 0x3) << 6
             | (AttrIndx & 0x7) << 2
             | (0x1 << 0);
@@ -224,10 +357,10 @@ impl PTE {
     ) -> PTE {
         let val = 0
             | (UXN & 0x1) << 54
-            | (page_base_address.raw() & 0xfffffffff000) >> 0
+            | desc_addr_encode(page_base_address.raw())
             | (nG & 0x1) << 11
             | (AF & 0x1) << 10
-            | (SH & 0x3) << 8
+            | desc_sh(SH)
             | (AP & 0x3) << 6
             | (AttrIndx & 0x7) << 2
             | 0x400000000000003;
@@ -255,3 +388,9 @@ impl PTE {
         ret
     }
 }
+
+/// Find the slot of `vptr` in the vspace whose root table is `vspace_root`.
+#[inline]
+pub(crate) fn lookup_vspace_slot(vspace_root: *mut PTE, vptr: VPtr) -> lookupPTSlot_ret_t {
+    PTE::new_from_pte(vspace_root as usize).lookup_pt_slot(vptr)
+}
@@ -0,0 +1,191 @@
+//! Stage 2 translation (IPA -> PA) used for guest vspaces when the `hypervisor` feature is on.
+//!
+//! Guest-physical vspaces are walked by the hardware through `VTTBR_EL2`, so their leaves are
+//! encoded with the stage 2 attributes (S2AP, MemAttr, XN, SH) instead of the stage 1 ones.
+use core::arch::asm;
+
+use rel4_arch::basic::PAddr;
+use sel4_common::arch::vm_rights_t;
+
+use super::granule::{
+    granule_level_shift, id_aa64mmfr0, tcr_fit_ps, GRANULE_BITS, GRANULE_INDEX_BITS, TCR_TG0,
+};
+use super::pa::{desc_addr_encode, desc_sh, PA_LPA2, TCR_PS};
+use super::{isb, mair_types};
+use crate::{pte_sw_bit_t, PTE};
+
+/// Size of the intermediate physical address space of the guests.
+pub const IPA_SIZE_BITS: usize = 48;
+
+/// At most 16 tables can be concatenated at the starting level.
+const S2_MAX_CONCAT_BITS: usize = 4;
+
+/// Bits of the address translated below a table of level `level`.
+#[inline]
+const fn s2_level_shift(level: usize) -> usize {
+    granule_level_shift(level)
+}
+
+/// Starting level of the stage 2 walk for `ipa_bits` of IPA.
+///
+/// Concatenating up to 16 tables lets a level resolve 4 more bits,
+/// except at level 0 where concatenation is not allowed.
+/// A walk starting at level 0 with the 64 KiB granule would need more than 52 bits.
+#[inline]
+pub const fn s2_start_level(ipa_bits: usize) -> usize {
+    if ipa_bits > s2_level_shift(0) + S2_MAX_CONCAT_BITS {
+        0
+    } else if ipa_bits > s2_level_shift(1) + S2_MAX_CONCAT_BITS {
+        1
+    } else {
+        2
+    }
+}
+
+/// Index bits resolved by the (possibly concatenated) root table.
+#[inline]
+pub const fn s2_root_index_bits(ipa_bits: usize) -> usize {
+    ipa_bits - s2_level_shift(s2_start_level(ipa_bits))
+}
+
+/// Number of concatenated tables forming the root.
+#[inline]
+pub const fn s2_concat_tables(ipa_bits: usize) -> usize {
+    let bits = s2_root_index_bits(ipa_bits);
+    if bits > GRANULE_INDEX_BITS {
+        bit!(bits - GRANULE_INDEX_BITS)
+    } else {
+        1
+    }
+}
+
+/// Size bits of the stage 2 root, `VTTBR_EL2.BADDR` has to be aligned to it.
+pub const S2_ROOT_TABLE_BITS: usize =
+    GRANULE_BITS + s2_concat_tables(IPA_SIZE_BITS).trailing_zeros() as usize;
+
+/// Value of `VTCR_EL2` describing the stage 2 tables of `ipa_bits` of IPA:
+/// the selected granule, inner shareable, write-back cacheable walks and `PA_BITS` of output.
+pub const fn vtcr_value(ipa_bits: usize) -> usize {
+    // SL0 for the 4 KiB granule: 0b10 starts at level 0, 0b01 at level 1, 0b00 at level 2.
+    // For the 16 KiB and 64 KiB granules it is one more: 0b11 starts at level 0, 0b00 at 3.
+    let sl0 = if GRANULE_BITS == 12 {
+        2 - s2_start_level(ipa_bits)
+    } else {
+        3 - s2_start_level(ipa_bits)
+    };
+    bit!(31)                    // RES1
+        | ((PA_LPA2 as usize) << 32) // DS: LPA2 descriptors
+        | (TCR_PS << 16)
+        | (TCR_TG0 << 14)
+        | (0b11 << 12)          // SH0: inner shareable
+        | (0b01 << 10)          // ORGN0: write-back write-allocate
+        | (0b01 << 8)           // IRGN0: write-back write-allocate
+        | (sl0 << 6)
+        | (64 - ipa_bits) // T0SZ
+}
+
+/// Configure the stage 2 translation regime for guests, with the output size lowered to what
+/// the CPU implements. See `init_mmu_registers`.
+#[inline]
+pub fn set_vtcr() {
+    let value = tcr_fit_ps(vtcr_value(IPA_SIZE_BITS), 16, id_aa64mmfr0());
+    unsafe {
+        asm!("msr vtcr_el2, {}", in(reg) value);
+    }
+    isb();
+}
+
+/// Stage 2 access permissions, S2AP[7:6] of a leaf descriptor.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum s2ap_t {
+    None = 0,
+    ReadOnly = 1,
+    WriteOnly = 2,
+    ReadWrite = 3,
+}
+
+impl s2ap_t {
+    /// Guests run at EL1/EL0 behind stage 2, so `VMKernelOnly` gives them no access at all.
+    pub fn from_vm_rights(rights: vm_rights_t) -> Self {
+        match rights {
+            vm_rights_t::VMKernelOnly => Self::None,
+            vm_rights_t::VMReadOnly => Self::ReadOnly,
+            vm_rights_t::VMReadWrite => Self::ReadWrite,
+        }
+    }
+}
+
+impl mair_types {
+    /// Stage 2 MemAttr[5:2] equivalent to the MAIR_EL1 type.
+    pub const fn stage2_mem_attr(&self) -> usize {
+        match self {
+            mair_types::DEVICE_nGnRnE => 0b0000,
+            mair_types::DEVICE_nGnRE => 0b0001,
+            mair_types::DEVICE_GRE => 0b0011,
+            mair_types::NORMAL_NC => 0b0101,
+            mair_types::NORMAL => 0b1111,
+            mair_types::NORMAL_WT => 0b1010,
+        }
+    }
+}
+
+/// S2AP[1], write access from the guest.
+const S2AP_WRITE: usize = bit!(7);
+
+impl PTE {
+    /// The writable leaf write-protected for dirty logging, `None` if unchanged.
+    pub(crate) fn dirty_log_protected(&self) -> Option<Self> {
+        if !self.pte_is_page_type() || self.0 & S2AP_WRITE == 0 {
+            return None;
+        }
+        Some(PTE(self.0 & !S2AP_WRITE).with_sw_bit(pte_sw_bit_t::DirtyLog, true))
+    }
+
+    /// The leaf with the write access taken by dirty logging given back, `None` if unchanged.
+    pub(crate) fn dirty_log_unprotected(&self) -> Option<Self> {
+        if !self.pte_is_page_type() || !self.get_sw_bit(pte_sw_bit_t::DirtyLog) {
+            return None;
+        }
+        Some(PTE(self.0 | S2AP_WRITE).with_sw_bit(pte_sw_bit_t::DirtyLog, false))
+    }
+
+    /// Create a stage 2 block descriptor.
+    pub fn pte_new_s2_page(
+        XN: usize,
+        page_base_address: PAddr,
+        AF: usize,
+        SH: usize,
+        S2AP: usize,
+        MemAttr: usize,
+    ) -> PTE {
+        let val = 0
+            | (XN & 0x1) << 54
+            | desc_addr_encode(page_base_address.raw())
+            | (AF & 0x1) << 10
+            | desc_sh(SH)
+            | (S2AP & 0x3) << 6
+            | (MemAttr & 0xf) << 2
+            | (0x1 << 0);
+        PTE(val)
+    }
+
+    /// Create a stage 2 page descriptor, tagged as a 4k page like the stage 1 ones.
+    pub fn pte_new_s2_4k_page(
+        XN: usize,
+        page_base_address: PAddr,
+        AF: usize,
+        SH: usize,
+        S2AP: usize,
+        MemAttr: usize,
+    ) -> PTE {
+        let val = 0
+            | (XN & 0x1) << 54
+            | desc_addr_encode(page_base_address.raw())
+            | (AF & 0x1) << 10
+            | desc_sh(SH)
+            | (S2AP & 0x3) << 6
+            | (MemAttr & 0xf) << 2
+            | 0x400000000000003;
+        PTE(val)
+    }
+}
@@ -7,24 +7,74 @@ use sel4_common::{
 
 use super::machine::mair_types;
 
+/// `seL4_ARM_VMAttributes`: bit 0 cacheable, bit 1 parity enabled, bit 2 execute-never,
+/// bits[5:3] an explicit memory type (MAIR index + 1, 0 selects by the cacheable bit).
 impl vm_attributes_t {
+    pub const PAGE_CACHEABLE: usize = bit!(0);
+    pub const PARITY_ENABLED: usize = bit!(1);
+    pub const EXECUTE_NEVER: usize = bit!(2);
+    pub const MEMORY_TYPE_SHIFT: usize = 3;
+    pub const MEMORY_TYPE: usize = 0x7 << Self::MEMORY_TYPE_SHIFT;
+    pub const VALID_BITS: usize =
+        Self::PAGE_CACHEABLE | Self::PARITY_ENABLED | Self::EXECUTE_NEVER | Self::MEMORY_TYPE;
+
+    pub const fn new_arm(cacheable: bool, parity_enabled: bool, execute_never: bool) -> Self {
+        Self(
+            (cacheable as usize) * Self::PAGE_CACHEABLE
+                | (parity_enabled as usize) * Self::PARITY_ENABLED
+                | (execute_never as usize) * Self::EXECUTE_NEVER,
+        )
+    }
+
+    /// Attributes selecting `memory_type` directly, the cacheable bit is kept consistent with it.
+    pub const fn new_arm_with_type(
+        memory_type: mair_types,
+        parity_enabled: bool,
+        execute_never: bool,
+    ) -> Self {
+        Self(
+            Self::new_arm(memory_type.is_cacheable(), parity_enabled, execute_never).0
+                | (memory_type as usize + 1) << Self::MEMORY_TYPE_SHIFT,
+        )
+    }
+
     pub fn get_arm_execute_never(&self) -> bool {
-        if (self.0 & 0x4) != 0 {
-            true
-        } else {
-            false
-        }
+        self.0 & Self::EXECUTE_NEVER != 0
     }
 
     pub fn get_arm_page_cachable(&self) -> bool {
-        if (self.0 & 0x1) != 0 {
-            true
-        } else {
-            false
+        self.0 & Self::PAGE_CACHEABLE != 0
+    }
+
+    pub fn get_arm_parity_enabled(&self) -> bool {
+        self.0 & Self::PARITY_ENABLED != 0
+    }
+
+    /// The explicitly selected memory type, `None` when the cacheable bit decides.
+    /// Reserved selector 7 is treated as the most restrictive, DEVICE_nGnRnE.
+    pub fn get_arm_memory_type(&self) -> Option<mair_types> {
+        match (self.0 & Self::MEMORY_TYPE) >> Self::MEMORY_TYPE_SHIFT {
+            0 => None,
+            n => Some(mair_types::from_index(n - 1).unwrap_or(mair_types::DEVICE_nGnRnE)),
+        }
+    }
+
+    pub fn set_arm_memory_type(&mut self, memory_type: Option<mair_types>) {
+        self.0 &= !Self::MEMORY_TYPE;
+        if let Some(memory_type) = memory_type {
+            self.0 |= (memory_type as usize + 1) << Self::MEMORY_TYPE_SHIFT;
         }
     }
 
+    pub(crate) fn is_page_cacheable(&self) -> bool {
+        self.get_attr_index().is_cacheable()
+    }
+
     pub fn get_attr_index(&self) -> mair_types {
+        if let Some(memory_type) = self.get_arm_memory_type() {
+            return memory_type;
+        }
+
         if self.get_arm_page_cachable() {
             return mair_types::NORMAL;
         }
@@ -77,3 +127,42 @@ pub(super) fn asid_pool_from_addr(addr: usize) -> &'static mut asid_pool_t {
     assert_ne!(addr, 0);
     convert_to_mut_type_ref(addr)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn memory_type_selects_the_attr_index() {
+        assert_eq!(
+            vm_attributes_t::new_arm(true, false, false).get_attr_index(),
+            mair_types::NORMAL
+        );
+        assert_eq!(
+            vm_attributes_t::new_arm(false, false, false).get_attr_index(),
+            mair_types::DEVICE_nGnRnE
+        );
+        for memory_type in mair_types::ALL {
+            let attr = vm_attributes_t::new_arm_with_type(memory_type, false, true);
+            assert!(attr.is_valid());
+            assert!(attr.get_arm_execute_never());
+            assert_eq!(attr.get_arm_memory_type(), Some(memory_type));
+            assert_eq!(attr.get_attr_index(), memory_type);
+            assert_eq!(attr.get_arm_page_cachable(), memory_type.is_cacheable());
+        }
+    }
+
+    #[test]
+    fn set_memory_type_round_trips() {
+        let mut attr = vm_attributes_t::new_arm(true, true, false);
+        attr.set_arm_memory_type(Some(mair_types::NORMAL_WT));
+        assert_eq!(attr.get_arm_memory_type(), Some(mair_types::NORMAL_WT));
+        assert!(attr.get_arm_parity_enabled());
+        attr.set_arm_memory_type(None);
+        assert_eq!(attr.get_arm_memory_type(), None);
+        assert_eq!(attr, vm_attributes_t::new_arm(true, true, false));
+        // Reserved selector 7 falls back to the most restrictive type.
+        let reserved = vm_attributes_t::new(0x7 << vm_attributes_t::MEMORY_TYPE_SHIFT);
+        assert_eq!(reserved.get_attr_index(), mair_types::DEVICE_nGnRnE);
+    }
+}
@@ -6,10 +6,50 @@ use sel4_common::{
     utils::convert_to_mut_slice,
 };
 
-pub const KPT_LEVELS: usize = 4;
-pub const UPT_LEVELS: usize = 4;
-pub const VSPACE_INDEX_BITS: usize = 9;
-pub(self) const PAGE_ADDR_MASK: usize = mask_bits!(48) & !0xfff;
+use super::granule::{
+    granule_level_shift, granule_root_index_bits, granule_start_level, GRANULE_ENTRIES,
+    GRANULE_INDEX_BITS, LEVEL1_BLOCKS, VA_BITS,
+};
+use super::pa::{desc_addr_decode, desc_addr_encode, DESC_ADDR_MASK};
+
+/// ARM level of the kernel root table, 1 with the 64 KiB granule.
+pub const KPT_START_LEVEL: usize = granule_start_level(VA_BITS);
+pub const KPT_LEVELS: usize = 4 - KPT_START_LEVEL;
+/// Index bits resolved by the kernel root table.
+pub const KPT_ROOT_INDEX_BITS: usize = granule_root_index_bits(VA_BITS);
+#[cfg(not(feature = "hypervisor"))]
+pub const UPT_LEVELS: usize = KPT_LEVELS;
+/// User vspaces are stage 2 tables, their walk starts at the level required by the IPA size.
+#[cfg(feature = "hypervisor")]
+pub const UPT_LEVELS: usize = 4 - super::s2_start_level(super::IPA_SIZE_BITS);
+#[cfg(not(feature = "hypervisor"))]
+pub const VSPACE_INDEX_BITS: usize = KPT_ROOT_INDEX_BITS;
+/// The stage 2 root may be made of concatenated tables, resolving more than a table's bits.
+#[cfg(feature = "hypervisor")]
+pub const VSPACE_INDEX_BITS: usize = super::s2_root_index_bits(super::IPA_SIZE_BITS);
+/// End of the user range: the TTBR0 range.
+#[cfg(not(feature = "hypervisor"))]
+pub const USER_TOP: usize = bit!(VA_BITS);
+/// End of the user range: the IPA range of the stage 2 vspaces.
+#[cfg(feature = "hypervisor")]
+pub const USER_TOP: usize = bit!(super::IPA_SIZE_BITS);
+/// Start of the TTBR1 range, which belongs to the kernel.
+#[cfg(not(feature = "hypervisor"))]
+pub const KERNEL_HALF_BASE: Option<usize> = Some(!mask_bits!(VA_BITS));
+/// IPAs have no kernel half, anything above `USER_TOP` is out of range.
+#[cfg(feature = "hypervisor")]
+pub const KERNEL_HALF_BASE: Option<usize> = None;
+/// Descriptor bits holding the output address, see `super::pa`.
+pub(super) const PAGE_ADDR_MASK: usize = DESC_ADDR_MASK;
+
+/// Bits of each `PageSize`, indexed by the size field of the frame cap.
+pub(crate) const PAGE_SIZE_BITS: [usize; 3] = [
+    SEL4_PAGE_BITS,
+    SEL4_PAGE_BITS + PT_INDEX_BITS,
+    SEL4_PAGE_BITS + 2 * PT_INDEX_BITS,
+];
+/// Level of the leaf entry of each `PageSize` in the user page table walk.
+pub(crate) const PAGE_SIZE_LEVELS: [usize; 3] = [UPT_LEVELS - 1, UPT_LEVELS - 2, UPT_LEVELS - 3];
 #[inline]
 pub fn ulvl_frm_arm_pt_lvl(n: usize) -> usize {
     n
@@ -39,9 +79,10 @@ pub fn get_pud_index(addr: usize) -> usize {
 pub fn get_pgd_index(addr: usize) -> usize {
     (addr >> PGD_INDEX_OFFSET) & mask_bits!(PGD_INDEX_BITS)
 }
+/// The kernel tables are indexed by ARM level, `n` is 0-3 whatever the root level is.
 #[inline]
 pub fn kpt_level_shift(n: usize) -> usize {
-    ((PT_INDEX_BITS) * (((KPT_LEVELS) - 1) - (n))) + SEL4_PAGE_BITS
+    granule_level_shift(n)
 }
 #[inline]
 pub fn upt_level_shift(n: usize) -> usize {
@@ -63,30 +104,40 @@ pub fn kpptr_to_paddr(x: usize) -> PAddr {
 
 impl VAddr {
     pub(super) fn get_kpt_index(&self, n: usize) -> usize {
-        ((self.0) >> (kpt_level_shift(n))) & mask_bits!(PT_INDEX_BITS)
+        let index_bits = if n == KPT_START_LEVEL {
+            KPT_ROOT_INDEX_BITS
+        } else {
+            GRANULE_INDEX_BITS
+        };
+        ((self.0) >> (kpt_level_shift(n))) & mask_bits!(index_bits)
     }
     pub(super) fn get_upt_index(&self, n: usize) -> usize {
-        ((self.0) >> (upt_level_shift(n))) & mask_bits!(PT_INDEX_BITS)
+        let index_bits = if n == 0 {
+            VSPACE_INDEX_BITS
+        } else {
+            PT_INDEX_BITS
+        };
+        ((self.0) >> (upt_level_shift(n))) & mask_bits!(index_bits)
     }
 
-    /// Get the index of the pt(last level, bit 12..20)
+    /// Get the index of the pt(last level, bit 12..20 with the 4 KiB granule)
     pub(super) const fn pt_index(&self) -> usize {
-        (self.0 >> 12) & 0x1ff
+        (self.0 >> granule_level_shift(3)) & mask_bits!(GRANULE_INDEX_BITS)
     }
 
-    /// Get the index of the pd(third level, bit 21..29)
+    /// Get the index of the pd(third level, bit 21..29 with the 4 KiB granule)
     pub(super) const fn pd_index(&self) -> usize {
-        (self.0 >> 21) & 0x1ff
+        (self.0 >> granule_level_shift(2)) & mask_bits!(GRANULE_INDEX_BITS)
     }
 
-    /// Get the index of the pud(second level, bit 30..38)
+    /// Get the index of the pud(second level, bit 30..38 with the 4 KiB granule)
     pub(super) const fn pud_index(&self) -> usize {
-        (self.0 >> 30) & 0x1ff
+        (self.0 >> granule_level_shift(1)) & mask_bits!(GRANULE_INDEX_BITS)
     }
 
-    /// Get the index of the pgd(first level, bit 39..47)
+    /// Get the index of the pgd(first level, bit 39..47 with the 4 KiB granule)
     pub(super) const fn pgd_index(&self) -> usize {
-        (self.0 >> 39) & 0x1ff
+        (self.0 >> granule_level_shift(0)) & mask_bits!(GRANULE_INDEX_BITS)
     }
 }
 
@@ -94,11 +145,10 @@ impl VAddr {
 ///
 /// Addr should be virtual address.
 pub(super) fn page_slice<T>(addr: PPtr) -> &'static mut [T] {
-    // The size of the page_table is 4K
+    // The size of the page_table is the granule
     // The size of the item is sizeof::<usize>() bytes
-    // 4096 / sizeof::<usize>() == 512
-    // So the len is 512
-    convert_to_mut_slice::<T>(addr.raw(), 0x200)
+    // So the len is GRANULE_ENTRIES, 512 with the 4 KiB granule
+    convert_to_mut_slice::<T>(addr.raw(), GRANULE_ENTRIES)
 }
 
 pub fn ap_from_vm_rights(rights: vm_rights_t) -> usize {
@@ -110,15 +160,27 @@ pub fn ap_from_vm_rights(rights: vm_rights_t) -> usize {
     rights as usize
 }
 
-// #[repr(C)]
-// #[derive(Debug, Clone, Copy)]
-// pub struct PGDE(pub usize);
-// #[repr(C)]
-// #[derive(Debug, Clone, Copy)]
-// pub struct PUDE(pub usize);
-// #[repr(C)]
-// #[derive(Debug, Clone, Copy)]
-// pub struct PDE(pub usize);
+/// Level 0 descriptor (page global directory entry).
+///
+/// A level 0 entry can only be invalid or point to a PUD, the 64 KiB granule has no level 0.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct PGDE(pub usize);
+/// Level 1 descriptor (page upper directory entry).
+///
+/// Either points to a PD or maps a 1 GiB block, blocks only exist with the 4 KiB granule.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct PUDE(pub usize);
+/// Level 2 descriptor (page directory entry).
+///
+/// Either points to a PT or maps a 2 MiB block (32 MiB / 512 MiB with 16 KiB / 64 KiB granules).
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct PDE(pub usize);
+/// Level 3 descriptor (page table entry), or an untyped descriptor when walking generically.
+///
+/// At level 3 it can only be invalid or map a page of the granule size.
 #[repr(C)]
 #[derive(Debug, Clone, Copy)]
 pub struct PTE(pub usize);
@@ -127,16 +189,191 @@ pub struct PTE(pub usize);
 #[derive(Debug, Clone)]
 pub struct ASID(usize);
 
-// // Implemente generic function for PGDE PUDE PDE
-// impl_multi!(PGDE, PUDE, PDE {
-//     /// Get the slice of the next level page.
-//     ///
-//     /// PGDE -> PUDE[PAGE_ITEMS]
-//     #[inline]
-//     pub fn next_level_slice<T>(&self) -> &'static mut [T] {
-//         page_slice(paddr_to_pptr(self.next_level_paddr()))
-//     }
-// });
+/// Bits[1:0] of a descriptor, which encode its type.
+pub(super) const DESC_TYPE_MASK: usize = 0x3;
+/// Table descriptor at level 0-2.
+pub(super) const DESC_TYPE_TABLE: usize = 0x3;
+/// Block descriptor at level 1-2.
+pub(super) const DESC_TYPE_BLOCK: usize = 0x1;
+/// Page descriptor at level 3, same encoding as a table at the upper levels.
+pub(super) const DESC_TYPE_PAGE: usize = 0x3;
+
+/// The kind of a translation table descriptor, decoded according to its level.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum desc_kind {
+    Invalid,
+    Table,
+    Block,
+    Page,
+}
+
+// Generic functions shared by every level.
+macro_rules! impl_descriptor {
+    ($($t:ident),*) => {
+        $(
+            impl $t {
+                #[inline]
+                pub const fn invalid() -> Self {
+                    Self(0)
+                }
+
+                /// Get the address of the self.
+                #[inline]
+                pub fn self_addr(&self) -> usize {
+                    self as *const _ as _
+                }
+
+                /// Get the next level paddr, or the output address of a block/page.
+                #[inline]
+                pub const fn next_level_paddr(&self) -> PAddr {
+                    paddr!(desc_addr_decode(self.0))
+                }
+
+                #[inline]
+                pub const fn is_present(&self) -> bool {
+                    !matches!(self.kind(), desc_kind::Invalid)
+                }
+
+                #[inline]
+                pub const fn is_table(&self) -> bool {
+                    matches!(self.kind(), desc_kind::Table)
+                }
+            }
+
+            impl From<$t> for PTE {
+                #[inline]
+                fn from(value: $t) -> Self {
+                    PTE(value.0)
+                }
+            }
+        )*
+    };
+}
+
+impl_descriptor!(PGDE, PUDE, PDE);
+
+impl PGDE {
+    #[inline]
+    pub const fn new_table(pud: PAddr) -> Self {
+        Self(desc_addr_encode(pud.raw()) | DESC_TYPE_TABLE)
+    }
+
+    #[inline]
+    pub const fn kind(&self) -> desc_kind {
+        match self.0 & DESC_TYPE_MASK {
+            DESC_TYPE_TABLE => desc_kind::Table,
+            _ => desc_kind::Invalid,
+        }
+    }
+
+    /// Get the slice of the next level page.
+    ///
+    /// PGDE -> PUDE[PAGE_ITEMS]
+    #[inline]
+    pub fn next_level_slice(&self) -> &'static mut [PUDE] {
+        assert!(self.is_table());
+        page_slice(self.next_level_paddr().to_pptr())
+    }
+}
+
+impl PUDE {
+    #[inline]
+    pub const fn new_table(pd: PAddr) -> Self {
+        Self(desc_addr_encode(pd.raw()) | DESC_TYPE_TABLE)
+    }
+
+    /// Create a 1 GiB block, `page_base_address` must be 1 GiB aligned.
+    ///
+    /// Only the 4 KiB granule has level 1 blocks.
+    #[inline]
+    pub fn new_1g_block(
+        UXN: usize,
+        page_base_address: PAddr,
+        nG: usize,
+        AF: usize,
+        SH: usize,
+        AP: usize,
+        AttrIndx: usize,
+    ) -> Self {
+        assert!(LEVEL1_BLOCKS);
+        assert_eq!(
+            page_base_address.raw() & mask_bits!(granule_level_shift(1)),
+            0
+        );
+        Self(PTE::pte_new_page(UXN, page_base_address, nG, AF, SH, AP, AttrIndx).0)
+    }
+
+    #[inline]
+    pub const fn kind(&self) -> desc_kind {
+        match self.0 & DESC_TYPE_MASK {
+            DESC_TYPE_TABLE => desc_kind::Table,
+            DESC_TYPE_BLOCK => desc_kind::Block,
+            _ => desc_kind::Invalid,
+        }
+    }
+
+    #[inline]
+    pub const fn is_1g_block(&self) -> bool {
+        matches!(self.kind(), desc_kind::Block)
+    }
+
+    /// Get the slice of the next level page.
+    ///
+    /// PUDE -> PDE[PAGE_ITEMS]
+    #[inline]
+    pub fn next_level_slice(&self) -> &'static mut [PDE] {
+        assert!(self.is_table());
+        page_slice(self.next_level_paddr().to_pptr())
+    }
+}
+
+impl PDE {
+    #[inline]
+    pub const fn new_table(pt: PAddr) -> Self {
+        Self(desc_addr_encode(pt.raw()) | DESC_TYPE_TABLE)
+    }
+
+    /// Create a 2 MiB block, `page_base_address` must be 2 MiB aligned.
+    #[inline]
+    pub fn new_2m_block(
+        UXN: usize,
+        page_base_address: PAddr,
+        nG: usize,
+        AF: usize,
+        SH: usize,
+        AP: usize,
+        AttrIndx: usize,
+    ) -> Self {
+        assert_eq!(
+            page_base_address.raw() & mask_bits!(granule_level_shift(2)),
+            0
+        );
+        Self(PTE::pte_new_page(UXN, page_base_address, nG, AF, SH, AP, AttrIndx).0)
+    }
+
+    #[inline]
+    pub const fn kind(&self) -> desc_kind {
+        match self.0 & DESC_TYPE_MASK {
+            DESC_TYPE_TABLE => desc_kind::Table,
+            DESC_TYPE_BLOCK => desc_kind::Block,
+            _ => desc_kind::Invalid,
+        }
+    }
+
+    #[inline]
+    pub const fn is_2m_block(&self) -> bool {
+        matches!(self.kind(), desc_kind::Block)
+    }
+
+    /// Get the slice of the next level page.
+    ///
+    /// PDE -> PTE[PAGE_ITEMS]
+    #[inline]
+    pub fn next_level_slice(&self) -> &'static mut [PTE] {
+        assert!(self.is_table());
+        page_slice(self.next_level_paddr().to_pptr())
+    }
+}
 
 impl PTE {
     #[inline]
@@ -152,7 +389,7 @@ impl PTE {
     /// Get the next level paddr
     #[inline]
     pub const fn next_level_paddr(&self) -> PAddr {
-        paddr!(self.0 & PAGE_ADDR_MASK)
+        paddr!(desc_addr_decode(self.0))
     }
     /// Set the next level paddr
     ///
@@ -160,7 +397,7 @@ impl PTE {
     /// Else it is the page to contains list
     #[inline]
     pub fn set_next_level_paddr(&mut self, addr: PAddr) {
-        self.0 = (self.0 & !PAGE_ADDR_MASK) | (addr.raw() & PAGE_ADDR_MASK);
+        self.0 = (self.0 & !PAGE_ADDR_MASK) | desc_addr_encode(addr.raw());
     }
     /// Set Page Attribute
     #[inline]
@@ -180,7 +417,7 @@ impl PTE {
     /// Create self through addr and attributes.
     #[inline]
     pub const fn new_page(addr: PAddr, sign: usize) -> Self {
-        Self((addr.raw() & PAGE_ADDR_MASK) | (sign & !PAGE_ADDR_MASK))
+        Self(desc_addr_encode(addr.raw()) | (sign & !PAGE_ADDR_MASK))
     }
 
     /// Get the page's type info
@@ -216,8 +453,22 @@ impl PTE {
         self.get_reserved() == 0x3
     }
 
+    /// Decode self as a level 3 descriptor.
+    #[inline]
+    pub const fn kind(&self) -> desc_kind {
+        match self.0 & DESC_TYPE_MASK {
+            DESC_TYPE_PAGE => desc_kind::Page,
+            _ => desc_kind::Invalid,
+        }
+    }
+
+    #[inline]
+    pub const fn is_4k_page(&self) -> bool {
+        matches!(self.kind(), desc_kind::Page)
+    }
+
     #[inline]
     pub const fn pte_ptr_get_page_base_address(&self) -> PAddr {
-        paddr!(self.0 & 0xfffffffff000)
+        paddr!(desc_addr_decode(self.0))
     }
 }
@@ -0,0 +1,139 @@
+//! Hardware VMID allocation for guest vspaces under the `hypervisor` feature.
+//!
+//! Each vspace installed in `VTTBR_EL2` is tagged with a VMID stored in its `asid_map` entry, so
+//! switching between guests does not need to flush the TLB. When all VMIDs are in use the next
+//! one in round-robin order is stolen from its owner and its TLB entries are flushed, on every
+//! core with `enable_smp`.
+//!
+//! Like the rest of the kernel state the allocator is only used with the kernel lock held, every
+//! kernel entry is serialised by it.
+use rel4_utils::no_lock::NoLock;
+use sel4_common::{
+    sel4_config::ASID_LOW_BITS,
+    structures_gen::{asid_map, asid_map_Splayed},
+    utils::convert_to_option_mut_type_ref,
+};
+
+use super::get_asid_pool_by_index;
+use crate::{asid_pool_t, asid_t};
+
+/// Width of the VMIDs handed out, that of the `stored_hw_vmid` field of `asid_map`.
+/// `VTCR_EL2.VS` is left clear, so `VTTBR_EL2` takes 8-bit VMIDs.
+pub const VMID_BITS: usize = 8;
+pub const N_VMID: usize = bit!(VMID_BITS);
+
+/// `asidInvalid`, marks a free VMID.
+const ASID_INVALID: asid_t = 0;
+
+struct vmid_state_t {
+    /// Owner of every hardware VMID.
+    owners: [asid_t; N_VMID],
+    /// Where the search for a free VMID starts, also the victim on rollover.
+    next: usize,
+    /// Number of VMIDs the CPU implements, up to `N_VMID`.
+    count: usize,
+}
+
+static VMID_STATE: NoLock<vmid_state_t> = NoLock::new(vmid_state_t {
+    owners: [ASID_INVALID; N_VMID],
+    next: 0,
+    count: N_VMID,
+});
+
+/// VMID width implemented by the CPU, from `ID_AA64MMFR1_EL1.VMIDBits`.
+fn hw_vmid_bits() -> usize {
+    let mmfr1: usize;
+    unsafe {
+        core::arch::asm!("mrs {}, id_aa64mmfr1_el1", out(reg) mmfr1);
+    }
+    // VMIDBits [7:4]: 0b0000 for 8 bits, 0b0010 for 16 bits.
+    match (mmfr1 >> 4) & 0xf {
+        0b0010 => 16,
+        _ => 8,
+    }
+}
+
+/// Size the VMID space from the CPU, called once while the kernel vspace is activated.
+pub fn vmid_init() {
+    let state = VMID_STATE.no_lock();
+    state.count = bit!(VMID_BITS.min(hw_vmid_bits()));
+    state.next = 0;
+}
+
+#[inline]
+fn find_map_for_asid_mut(asid: asid_t) -> Option<&'static mut asid_map> {
+    convert_to_option_mut_type_ref::<asid_pool_t>(get_asid_pool_by_index(asid >> ASID_LOW_BITS))
+        .map(|pool| &mut pool[asid & mask_bits!(ASID_LOW_BITS)])
+}
+
+/// Get the VMID currently stored in the vspace entry of `asid`.
+pub fn find_hw_vmid(asid: asid_t) -> Option<usize> {
+    match find_map_for_asid_mut(asid)?.clone().splay() {
+        asid_map_Splayed::asid_map_vspace(data) if data.get_stored_vmid_valid() != 0 => {
+            Some(data.get_stored_hw_vmid() as usize)
+        }
+        _ => None,
+    }
+}
+
+fn store_hw_vmid(asid: asid_t, vmid: usize) {
+    if let Some(map) = find_map_for_asid_mut(asid) {
+        if let asid_map_Splayed::asid_map_vspace(mut data) = map.clone().splay() {
+            data.set_stored_hw_vmid(vmid as u64);
+            data.set_stored_vmid_valid(1);
+            *map = data.unsplay();
+        }
+    }
+    VMID_STATE.no_lock().owners[vmid] = asid;
+}
+
+/// Drop the VMID stored in the vspace entry of `asid`, without touching the TLB.
+fn invalidate_asid_entry(asid: asid_t) {
+    if let Some(map) = find_map_for_asid_mut(asid) {
+        if let asid_map_Splayed::asid_map_vspace(mut data) = map.clone().splay() {
+            data.set_stored_vmid_valid(0);
+            *map = data.unsplay();
+        }
+    }
+}
+
+fn find_free_hw_vmid() -> usize {
+    let state = VMID_STATE.no_lock();
+    for i in 0..state.count {
+        let vmid = (state.next + i) % state.count;
+        if state.owners[vmid] == ASID_INVALID {
+            state.next = (vmid + 1) % state.count;
+            return vmid;
+        }
+    }
+    // Rollover: steal the next VMID from its owner. Its entries are flushed while the owner
+    // still holds the VMID, so that other cores find it as well.
+    let vmid = state.next;
+    let owner = state.owners[vmid];
+    super::invalidate_tlb_by_asid(owner);
+    invalidate_asid_entry(owner);
+    state.owners[vmid] = ASID_INVALID;
+    state.next = (vmid + 1) % state.count;
+    vmid
+}
+
+/// Get the VMID of `asid`, allocating one if the vspace has none yet.
+pub fn get_hw_vmid(asid: asid_t) -> usize {
+    match find_hw_vmid(asid) {
+        Some(vmid) => vmid,
+        None => {
+            let vmid = find_free_hw_vmid();
+            store_hw_vmid(asid, vmid);
+            vmid
+        }
+    }
+}
+
+/// Release the VMID of `asid` and flush its TLB entries, used when the asid is deleted.
+pub fn invalidate_vmid_entry(asid: asid_t) {
+    if let Some(vmid) = find_hw_vmid(asid) {
+        super::invalidate_tlb_by_asid(asid);
+        VMID_STATE.no_lock().owners[vmid] = ASID_INVALID;
+        invalidate_asid_entry(asid);
+    }
+}
@@ -39,9 +39,13 @@ pub fn delete_asid(
     unsafe {
         let poolPtr = riscvKSASIDTable[asid >> ASID_LOW_BITS];
         if poolPtr as usize != 0 && (*poolPtr).array[asid & mask_bits!(ASID_LOW_BITS)] == vspace {
-            #[cfg(target_arch = "riscv64")]
+            #[cfg(all(target_arch = "riscv64", not(feature = "hypervisor")))]
             hw_asid_flush(asid);
+            #[cfg(feature = "hypervisor")]
+            super::invalidate_vmid_entries(asid, 1);
             (*poolPtr).array[asid & mask_bits!(ASID_LOW_BITS)] = 0 as *mut PTE;
+            #[cfg(feature = "reverse_map")]
+            crate::rmap_remove_asids(asid, 1);
             set_vm_root(&default_vspace_cap)
         } else {
             Ok(())
@@ -115,6 +119,10 @@ pub fn delete_asid_pool(
     unsafe {
         if riscvKSASIDTable[asid_base >> ASID_LOW_BITS] == pool {
             riscvKSASIDTable[asid_base >> ASID_LOW_BITS] = 0 as *mut asid_pool_t;
+            #[cfg(feature = "hypervisor")]
+            super::invalidate_vmid_entries(asid_base, bit!(ASID_LOW_BITS));
+            #[cfg(feature = "reverse_map")]
+            crate::rmap_remove_asids(asid_base, bit!(ASID_LOW_BITS));
             set_vm_root(default_vspace_cap)
         } else {
             Ok(())
@@ -1,26 +1,53 @@
-use super::utils::riscv_get_lvl_pgsize_bits;
-use crate::arch::riscv64::pagetable::KERNEL_ROOT_PAGE_TABLE;
-use crate::{riscv_get_pt_index, sfence, PTEFlags, PTE};
+use super::utils::{riscv_get_lvl_pgsize, riscv_get_lvl_pgsize_bits};
+use crate::arch::riscv64::pagetable::{KERNEL_DEVICE_LEVEL2_PT, KERNEL_DEVICE_LEVEL3_PT};
+use crate::utils::vm_rights_from_word;
+use crate::{pbmt_t, riscv_get_pt_index, sfence, vm_attributes_t, PTEFlags, PageSize, PTE};
 use rel4_arch::basic::{PAddr, PPtr, VPtr};
 use sel4_common::structures_gen::{cap_frame_cap, cap_page_table_cap};
 use sel4_common::{
-    arch::config::KDEV_BASE,
-    arch::vm_rights_t,
-    sel4_config::{RISCV_MEGA_PAGE_BITS, RISCV_PAGE_BITS, SEL4_PAGE_BITS},
+    arch::config::KDEV_BASE, arch::vm_rights_t, sel4_config::SEL4_PAGE_BITS,
     utils::convert_to_mut_type_ref,
 };
 
+/// 内核设备虚地址所在的页表级数：`KDEV_BASE`开始的`2MiB`为第 2 级（`4KiB`），
+/// 其余设备区域为第 1 级（`2MiB`），设备区域只有`kernel_device_level2_pt`覆盖的`1GiB`
+///
+/// The level of the leaf mapping the kernel device address `vaddr`, which has to lie in the
+/// 1 GiB covered by `kernel_device_level2_pt`.
+pub(crate) fn kernel_frame_level(vaddr: usize) -> usize {
+    assert!(
+        vaddr >= KDEV_BASE && vaddr - KDEV_BASE < riscv_get_lvl_pgsize(0),
+        "kernel device address outside of the kernel device window"
+    );
+    if vaddr - KDEV_BASE >= riscv_get_lvl_pgsize(1) {
+        1
+    } else {
+        2
+    }
+}
+
+/// 将内核设备映射到内核地址空间中，设备页不可执行，读写权限由`vm_rights`决定，内存类型由`attributes`决定
+///
+/// `KDEV_BASE`开始的`2MiB`按`4KiB`映射在`kernel_device_level3_pt`中，
+/// 其余设备区域按`2MiB`映射在`kernel_device_level2_pt`中
+///
+/// Map a kernel device frame, never executable, readable and writable according to `vm_rights`,
+/// of the memory type selected by `attributes`.
 #[no_mangle]
 #[link_section = ".boot.text"]
-pub fn map_kernel_frame(paddr: PAddr, vaddr: usize, _vm_rights: vm_rights_t) {
-    if vaddr >= KDEV_BASE {
-        let paddr = paddr.align_down(riscv_get_lvl_pgsize_bits(1));
-        KERNEL_ROOT_PAGE_TABLE.no_lock()[riscv_get_pt_index(vaddr, 0)] =
-            PTE::pte_next_table(paddr, true);
+pub fn map_kernel_frame(
+    paddr: PAddr,
+    vaddr: usize,
+    vm_rights: vm_rights_t,
+    attributes: vm_attributes_t,
+) {
+    let level = kernel_frame_level(vaddr);
+    let paddr = paddr.align_down(riscv_get_lvl_pgsize_bits(level));
+    let pte = PTE::make_kernel_device_pte(paddr, vm_rights, attributes.get_riscv_memory_type());
+    if level == 1 {
+        KERNEL_DEVICE_LEVEL2_PT.no_lock()[riscv_get_pt_index(vaddr, 1)] = pte;
     } else {
-        let paddr = paddr.align_down(riscv_get_lvl_pgsize_bits(0));
-        KERNEL_ROOT_PAGE_TABLE.no_lock()[riscv_get_pt_index(vaddr, 0)] =
-            PTE::pte_next_table(paddr, true);
+        KERNEL_DEVICE_LEVEL3_PT.no_lock()[riscv_get_pt_index(vaddr, 2)] = pte;
     }
 }
 
@@ -36,20 +63,42 @@ pub fn map_it_pt_cap(vspace_cap: &cap_page_table_cap, pt_cap: &cap_page_table_ca
     sfence();
 }
 
+/// 将初始线程的`frame`映射到其地址空间中，页表项权限由`frame cap`的`VMRights`决定，页可执行
+///
+/// Map an executable frame of the initial thread, the other flags come from the rights of the
+/// frame cap.
 #[no_mangle]
 pub fn map_it_frame_cap(vspace_cap: &cap_page_table_cap, frame_cap: &cap_frame_cap) {
+    map_it_frame(vspace_cap, frame_cap, true);
+}
+
+/// 将初始线程的`frame`映射到其地址空间中，页表项权限由`frame cap`的`VMRights`和`exec`共同决定，
+/// 可执行的页不可写（W^X）
+///
+/// Map a frame of the initial thread, the flags come from the rights of the frame cap and `exec`.
+/// Executable frames are never mapped writable (W^X), whatever the rights of the cap.
+fn map_it_frame(vspace_cap: &cap_page_table_cap, frame_cap: &cap_frame_cap, exec: bool) {
     let vptr = vptr!(frame_cap.get_capFMappedAddress());
     let lvl1pt = convert_to_mut_type_ref::<PTE>(vspace_cap.get_capPTBasePtr() as usize);
     let frame_pptr = pptr!(frame_cap.get_capFBasePtr());
+    let vm_rights = match vm_rights_from_word(frame_cap.get_capFVMRights() as usize) {
+        vm_rights_t::VMReadWrite if exec => vm_rights_t::VMReadOnly,
+        vm_rights => vm_rights,
+    };
     let pt_ret = lvl1pt.lookup_pt_slot(vptr);
 
     let targetSlot = convert_to_mut_type_ref::<PTE>(pt_ret.ptSlot as usize);
 
-    *targetSlot = PTE::new(
-        frame_pptr.to_paddr().raw() >> SEL4_PAGE_BITS,
-        PTEFlags::ADUVRWX,
-    );
-    sfence();
+    let attr = vm_attributes_t::new_riscv(!exec, pbmt_t::PMA);
+    let pte = PTE::make_user_pte(frame_pptr.to_paddr(), vm_rights, attr);
+    let asid = frame_cap.get_capFMappedASID() as usize;
+    if let Err(err) = targetSlot.map_user_leaf(pte, PageSize::Small, asid, vptr) {
+        log::error!(
+            "initial thread frame at {:#x} not mapped: {:?}",
+            vptr.raw(),
+            err
+        );
+    }
 }
 
 #[no_mangle]
@@ -60,23 +109,22 @@ pub fn create_mapped_it_frame_cap(
     vptr: VPtr,
     asid: usize,
     use_large: bool,
-    _exec: bool,
+    exec: bool,
 ) -> cap_frame_cap {
-    let frame_size: usize;
-    if use_large {
-        frame_size = RISCV_MEGA_PAGE_BITS;
+    let frame_size = if use_large {
+        PageSize::Large
     } else {
-        frame_size = RISCV_PAGE_BITS;
-    }
+        PageSize::Small
+    };
     let capability = cap_frame_cap::new(
         asid as u64,
         pptr.as_u64(),
-        frame_size as u64,
+        frame_size.to_cap_size() as u64,
         vm_rights_t::VMReadWrite as u64,
         0,
         vptr.as_u64(),
     );
-    map_it_frame_cap(pd_cap, &capability);
+    map_it_frame(pd_cap, &capability, exec);
     capability
 }
 
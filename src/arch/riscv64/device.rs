@@ -1,24 +1,75 @@
-use super::boot::map_kernel_frame;
+use super::boot::{kernel_frame_level, map_kernel_frame};
+use super::pbmt_t;
+use super::utils::riscv_get_lvl_pgsize;
+use crate::{device_memory_t, kernel_device_error_t, kernel_device_frame_t, vm_attributes_t};
 use rel4_arch::basic::PRegion;
 use sel4_common::arch::vm_rights_t::VMKernelOnly;
 use sel4_common::platform::kernel_device_frames;
-use sel4_common::sel4_config::PAGE_BITS;
 
 extern "C" {
     pub(self) fn reserve_region(reg: PRegion) -> bool;
 }
 
+/// 设备类型均映射为`IO`，`NORMAL_NC`映射为`NC`
+///
+/// Device types map to `IO`, `NORMAL_NC` to `NC`.
+impl From<device_memory_t> for pbmt_t {
+    fn from(memory_type: device_memory_t) -> Self {
+        match memory_type {
+            device_memory_t::NORMAL_NC => pbmt_t::NC,
+            _ => pbmt_t::IO,
+        }
+    }
+}
+
 #[no_mangle]
 pub fn map_kernel_devices() {
     unsafe {
         for kernel_frame in kernel_device_frames {
-            map_kernel_frame(kernel_frame.paddr, kernel_frame.pptr.raw(), VMKernelOnly);
-            if kernel_frame.userAvailable == 0 {
-                reserve_region(PRegion::new(
-                    paddr!(kernel_frame.paddr.raw()),
-                    paddr!(kernel_frame.paddr.raw()) + bit!(PAGE_BITS),
-                ));
+            let ret = kernel_device_frame_t::from_platform(
+                kernel_frame.paddr,
+                kernel_frame.pptr,
+                true,
+                kernel_frame.userAvailable != 0,
+            )
+            .and_then(|frame| map_kernel_device_frame(&frame));
+            if let Err(err) = ret {
+                log::error!(
+                    "kernel device {:#x} not mapped: {:?}",
+                    kernel_frame.paddr.raw(),
+                    err
+                );
             }
         }
     }
 }
+
+/// 按内存类型映射一个内核设备区域，每个叶子的大小由其起始地址所在的页表级决定，
+/// 非用户可用时保留该区域
+///
+/// Map a kernel device region with its memory type, one leaf at a time. The size and alignment
+/// of each leaf come from the level covering its own address, so a region may cross from the
+/// 4 KiB part of the device window into the 2 MiB part. The region is reserved unless it is
+/// available to user level.
+pub fn map_kernel_device_frame(frame: &kernel_device_frame_t) -> Result<(), kernel_device_error_t> {
+    let vm_attr = vm_attributes_t::new_riscv(true, frame.memory_type.into());
+    let mut offset = 0;
+    while offset < frame.size() {
+        let pptr = frame.pptr.raw() + offset;
+        let leaf_size = riscv_get_lvl_pgsize(kernel_frame_level(pptr));
+        if ((frame.paddr.raw() + offset) ^ pptr) & (leaf_size - 1) != 0 {
+            return Err(kernel_device_error_t::Misaligned);
+        }
+        map_kernel_frame(frame.paddr + offset, pptr, VMKernelOnly, vm_attr);
+        offset += leaf_size - (pptr & (leaf_size - 1));
+    }
+    if !frame.user_available {
+        unsafe {
+            reserve_region(PRegion::new(
+                paddr!(frame.paddr.raw()),
+                paddr!(frame.paddr.raw()) + frame.size(),
+            ));
+        }
+    }
+    Ok(())
+}
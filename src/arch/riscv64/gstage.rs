@@ -0,0 +1,312 @@
+//! H 扩展的 G-stage（客户物理地址到宿主物理地址）页表与`hgatp`
+//!
+//! RISC-V H-extension G-stage (guest physical to host physical) tables and `hgatp`.
+//!
+//! G-stage leaves are always checked as user accesses, so they use `PTE::make_user_pte`.
+use rel4_arch::basic::{PAddr, PPtr};
+use rel4_utils::no_lock::NoLock;
+use sel4_common::sel4_config::{
+    CONFIG_PT_LEVELS, PT_INDEX_BITS, SEL4_PAGE_BITS, SEL4_PAGE_TABLE_BITS,
+};
+use sel4_common::utils::convert_to_mut_slice;
+
+#[cfg(feature = "hypervisor")]
+use crate::pte_sw_bit_t;
+use crate::{asid_t, PTEFlags, PTE};
+
+/// G-stage 根页表比普通页表多出的索引位数
+///
+/// Extra index bits of the G-stage root table, the `x4` of Sv39x4/Sv48x4.
+pub const GSTAGE_ROOT_EXTRA_BITS: usize = 2;
+/// G-stage 根页表的索引位数
+///
+/// Index bits of the G-stage root table.
+pub const GSTAGE_ROOT_INDEX_BITS: usize = PT_INDEX_BITS + GSTAGE_ROOT_EXTRA_BITS;
+/// G-stage 根页表的大小位数（16 KiB），根页表需要按此对齐
+///
+/// Size bits of the G-stage root table (16 KiB), which is also its required alignment.
+pub const GSTAGE_ROOT_TABLE_BITS: usize = SEL4_PAGE_TABLE_BITS + GSTAGE_ROOT_EXTRA_BITS;
+/// 客户物理地址的位数
+///
+/// Bits of a guest physical address.
+pub const GPA_BITS: usize =
+    PT_INDEX_BITS * CONFIG_PT_LEVELS + SEL4_PAGE_BITS + GSTAGE_ROOT_EXTRA_BITS;
+
+/// `hgatp`的`MODE`字段，Sv39x4 或 Sv48x4
+///
+/// `MODE` field of `hgatp`, Sv39x4 or Sv48x4 depending on the number of levels.
+pub const HGATP_MODE: usize = if CONFIG_PT_LEVELS == 3 { 8 } else { 9 };
+
+///获得客户物理地址`gpa`对应的`n`级页表索引，根页表（`n = 0`）有`GSTAGE_ROOT_INDEX_BITS`位
+///
+/// Get the level `n` G-stage index of `gpa`, the root (`n = 0`) one is 2 bits wider.
+#[inline]
+pub fn gstage_get_pt_index(gpa: usize, n: usize) -> usize {
+    let bits = if n == 0 {
+        GSTAGE_ROOT_INDEX_BITS
+    } else {
+        PT_INDEX_BITS
+    };
+    (gpa >> (PT_INDEX_BITS * (CONFIG_PT_LEVELS - 1 - n) + SEL4_PAGE_BITS)) & mask_bits!(bits)
+}
+
+#[cfg(feature = "hypervisor")]
+impl PTE {
+    ///为脏页记录写保护后的可写叶子页表项，未修改时为`None`
+    ///
+    /// The writable leaf write-protected for dirty logging, `None` if unchanged.
+    pub(crate) fn dirty_log_protected(&self) -> Option<Self> {
+        if self.get_valid() == 0 || self.is_pte_table() || self.get_write() == 0 {
+            return None;
+        }
+        Some(PTE(self.0 & !PTEFlags::W.bits()).with_sw_bit(pte_sw_bit_t::DirtyLog, true))
+    }
+
+    ///恢复脏页记录去掉的写权限后的叶子页表项，未修改时为`None`
+    ///
+    /// The leaf with the write access taken by dirty logging given back, `None` if unchanged.
+    pub(crate) fn dirty_log_unprotected(&self) -> Option<Self> {
+        if self.get_valid() == 0 || self.is_pte_table() || !self.get_sw_bit(pte_sw_bit_t::DirtyLog)
+        {
+            return None;
+        }
+        Some(PTE(self.0 | PTEFlags::W.bits()).with_sw_bit(pte_sw_bit_t::DirtyLog, false))
+    }
+}
+
+///初始化新的 G-stage 根页表`root`：检查`16 KiB`对齐并清空全部表项，客户地址空间中没有内核映射
+///
+/// Prepare the new G-stage root at `root`: check it is 16 KiB aligned and clear all its entries,
+/// guest vspaces get no kernel mapping.
+pub fn gstage_root_init(root: PPtr) {
+    assert_eq!(root.raw() & mask_bits!(GSTAGE_ROOT_TABLE_BITS), 0);
+    convert_to_mut_slice::<PTE>(root.raw(), bit!(GSTAGE_ROOT_INDEX_BITS)).fill(PTE(0));
+}
+
+///`hgatp`寄存器对应的内存备份
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct hgatp_t {
+    pub words: usize,
+}
+
+impl hgatp_t {
+    pub fn new(mode: usize, vmid: usize, ppn: usize) -> Self {
+        hgatp_t {
+            words: 0
+                | (mode & 0xfusize) << 60
+                | (vmid & 0x3fffusize) << 44
+                | (ppn & 0xfffffffffffusize) << 0,
+        }
+    }
+}
+
+///设置 G-stage 根页表，创建一个新的`hgatp`的值，写入`hgatp`寄存器
+///
+/// Install the G-stage root `addr` with `vmid`, from `get_hw_vmid`, into `hgatp`.
+#[inline]
+pub fn set_gstage_root(addr: PAddr, vmid: usize) {
+    assert_eq!(addr.raw() & mask_bits!(GSTAGE_ROOT_TABLE_BITS), 0);
+    let hgatp = hgatp_t::new(HGATP_MODE, vmid, addr.raw() >> SEL4_PAGE_BITS);
+    #[cfg(target_arch = "riscv64")]
+    unsafe {
+        core::arch::asm!("csrw 0x680, {0}", in(reg) hgatp.words);
+    }
+}
+
+/// 分配给客户地址空间的`VMID`的位数上限，`hgatp.VMID`实际实现的位数（`VMIDLEN`）可能更少
+///
+/// Width of the VMIDs handed out to guest vspaces, the CPU may implement fewer bits of
+/// `hgatp.VMID` (`VMIDLEN`). VMIDs are allocated apart from the ASIDs, which are wider.
+pub const VMID_BITS: usize = 8;
+pub const N_VMID: usize = bit!(VMID_BITS);
+
+/// `asidInvalid`，表示`VMID`空闲
+///
+/// `asidInvalid`, marks a free VMID.
+const ASID_INVALID: asid_t = 0;
+
+/// `VMID`分配器，与其他内核状态一样只在持有内核锁时使用
+///
+/// The VMID allocator, like the rest of the kernel state only used with the kernel lock held.
+struct vmid_state_t {
+    /// Owner of every VMID.
+    owners: [asid_t; N_VMID],
+    /// Where the search for a free VMID starts, also the victim on rollover.
+    next: usize,
+    /// Number of VMIDs the CPU implements, up to `N_VMID`.
+    count: usize,
+}
+
+static VMID_STATE: NoLock<vmid_state_t> = NoLock::new(vmid_state_t {
+    owners: [ASID_INVALID; N_VMID],
+    next: 0,
+    count: 1,
+});
+
+///探测`hgatp.VMID`实现的位数：写入全 1 后读回
+///
+/// Probe `VMIDLEN` by writing all ones to `hgatp.VMID` and reading it back.
+fn hw_vmid_bits() -> usize {
+    #[cfg(target_arch = "riscv64")]
+    {
+        let hgatp: usize;
+        unsafe {
+            core::arch::asm!(
+                "csrw 0x680, {0}",
+                "csrr {1}, 0x680",
+                "csrw 0x680, x0",
+                in(reg) hgatp_t::new(0, usize::MAX, 0).words,
+                out(reg) hgatp,
+            );
+        }
+        ((hgatp >> 44) & 0x3fff).count_ones() as usize
+    }
+    #[cfg(not(target_arch = "riscv64"))]
+    0
+}
+
+///根据`VMIDLEN`确定可分配的`VMID`数量，在激活内核地址空间时调用一次
+///
+/// Size the VMID space from `VMIDLEN`, called once while the kernel vspace is activated.
+pub fn vmid_init() {
+    let state = VMID_STATE.no_lock();
+    state.count = bit!(VMID_BITS.min(hw_vmid_bits()));
+    state.next = 0;
+}
+
+///获取`asid`当前的`VMID`
+///
+/// Get the VMID `asid` currently holds.
+pub fn find_hw_vmid(asid: asid_t) -> Option<usize> {
+    let state = VMID_STATE.no_lock();
+    state.owners[..state.count]
+        .iter()
+        .position(|&owner| owner == asid)
+}
+
+///获取`asid`的`VMID`，没有时分配一个；全部被占用时从下一个`VMID`的所有者处回收并刷新其`TLB`项
+///
+/// Get the VMID of `asid`, allocating one if it has none yet. When all VMIDs are in use the next
+/// one is stolen from its owner and its G-stage translations are flushed.
+pub fn get_hw_vmid(asid: asid_t) -> usize {
+    if let Some(vmid) = find_hw_vmid(asid) {
+        return vmid;
+    }
+    let state = VMID_STATE.no_lock();
+    let free = (0..state.count)
+        .map(|i| (state.next + i) % state.count)
+        .find(|&vmid| state.owners[vmid] == ASID_INVALID);
+    let vmid = match free {
+        Some(vmid) => vmid,
+        None => {
+            let vmid = state.next;
+            hfence_gvma_vmid_all(vmid);
+            vmid
+        }
+    };
+    state.owners[vmid] = asid;
+    state.next = (vmid + 1) % state.count;
+    vmid
+}
+
+///释放`[asid_base, asid_base + count)`中地址空间的`VMID`并刷新其`TLB`项，删除`asid`时调用
+///
+/// Release the VMIDs of the ASIDs in `[asid_base, asid_base + count)` and flush their G-stage
+/// translations, used when they are deleted.
+pub fn invalidate_vmid_entries(asid_base: asid_t, count: usize) {
+    let state = VMID_STATE.no_lock();
+    for vmid in 0..state.count {
+        let owner = state.owners[vmid];
+        if owner != ASID_INVALID && owner >= asid_base && owner - asid_base < count {
+            hfence_gvma_vmid_all(vmid);
+            state.owners[vmid] = ASID_INVALID;
+        }
+    }
+}
+
+// 工具链不一定支持 H 扩展，`hfence`以`.insn`编码：
+// hfence.gvma 的 funct7 为 0x31，hfence.vvma 的 funct7 为 0x11
+
+///对汇编指令`hfence.gvma`的简单封装，清空所有 G-stage 的`tlb`
+///
+/// Risc-v's hfence.gvma, flushing the G-stage translations of every VMID.
+#[inline]
+pub fn hfence_gvma() {
+    #[cfg(target_arch = "riscv64")]
+    unsafe {
+        core::arch::asm!(".insn r 0x73, 0, 0x31, x0, x0, x0");
+    }
+}
+
+///清除`TLB`中对应`vmid`的 G-stage 项
+///
+/// Flush the G-stage translations of `vmid`.
+#[inline]
+pub fn hfence_gvma_vmid(vmid: usize) {
+    #[cfg(target_arch = "riscv64")]
+    unsafe {
+        core::arch::asm!(".insn r 0x73, 0, 0x31, x0, x0, {0}", in(reg) vmid);
+    }
+}
+
+/// SBI RFENCE 扩展号与`remote_hfence_gvma_vmid`功能号
+#[cfg(feature = "enable_smp")]
+const SBI_EXT_RFENCE: usize = 0x5246_4e43;
+#[cfg(feature = "enable_smp")]
+const SBI_REMOTE_HFENCE_GVMA_VMID: usize = 3;
+
+///清除所有`hart`的`TLB`中对应`vmid`的 G-stage 项，其他`hart`通过 SBI RFENCE 扩展清除
+///
+/// Flush the G-stage translations of `vmid` on every hart, the remote ones through the SBI
+/// RFENCE extension with `enable_smp`.
+pub fn hfence_gvma_vmid_all(vmid: usize) {
+    hfence_gvma_vmid(vmid);
+    #[cfg(all(feature = "enable_smp", target_arch = "riscv64"))]
+    unsafe {
+        let mask = sel4_common::arch::get_sbi_mask_for_all_remote_harts();
+        core::arch::asm!(
+            "ecall",
+            inlateout("a0") mask as usize => _,
+            inlateout("a1") 0usize => _,
+            in("a2") 0usize,
+            in("a3") usize::MAX,
+            in("a4") vmid,
+            in("a6") SBI_REMOTE_HFENCE_GVMA_VMID,
+            in("a7") SBI_EXT_RFENCE,
+        );
+    }
+}
+
+///清除`TLB`中对应`vmid`与客户物理地址`gpa`的 G-stage 项，`rs1`为`gpa >> 2`
+///
+/// Flush the G-stage translation of `gpa` in `vmid`, `rs1` holds `gpa >> 2`.
+#[inline]
+pub fn hfence_gvma_gpa_vmid(gpa: usize, vmid: usize) {
+    #[cfg(target_arch = "riscv64")]
+    unsafe {
+        core::arch::asm!(".insn r 0x73, 0, 0x31, x0, {0}, {1}", in(reg) gpa >> 2, in(reg) vmid);
+    }
+}
+
+///对汇编指令`hfence.vvma`的简单封装，清空当前`hgatp.VMID`下所有 VS-stage 的`tlb`
+///
+/// Risc-v's hfence.vvma, flushing the VS-stage translations of the current `hgatp.VMID`.
+#[inline]
+pub fn hfence_vvma() {
+    #[cfg(target_arch = "riscv64")]
+    unsafe {
+        core::arch::asm!(".insn r 0x73, 0, 0x11, x0, x0, x0");
+    }
+}
+
+///清除当前`hgatp.VMID`下对应客户`asid`与虚拟地址`vaddr`的 VS-stage 项
+///
+/// Flush the VS-stage translation of `vaddr` in guest `asid`, under the current `hgatp.VMID`.
+#[inline]
+pub fn hfence_vvma_va_asid(vaddr: usize, asid: usize) {
+    #[cfg(target_arch = "riscv64")]
+    unsafe {
+        core::arch::asm!(".insn r 0x73, 0, 0x11, x0, {0}, {1}", in(reg) vaddr, in(reg) asid);
+    }
+}
@@ -1,8 +1,10 @@
 use crate::arch::riscv64::pagetable::KERNEL_ROOT_PAGE_TABLE;
 use crate::asid_t;
+use crate::check_user_vaddr;
 use crate::find_vspace_for_asid;
-use crate::riscv_get_pt_index;
 use crate::sfence;
+use crate::vaddr_error_t;
+use crate::vspace_get_pt_index;
 use crate::PTEFlags;
 use core::intrinsics::unlikely;
 use rel4_arch::basic::VPtr;
@@ -40,31 +42,73 @@ pub fn set_vm_root(vspace_root_cap: &cap) -> Result<(), lookup_fault> {
         }
         set_vspace_root(kpptr_to_paddr(KERNEL_ROOT_PAGE_TABLE.as_ptr() as usize), 0);
     }
+    #[cfg(not(feature = "hypervisor"))]
     set_vspace_root(pptr!(lvl1pt as *mut PTE).to_paddr(), asid);
+    // 客户地址空间为 G-stage 页表，以分配给`asid`的`VMID`写入`hgatp`，`satp`保持为内核页表
+    #[cfg(feature = "hypervisor")]
+    {
+        set_vspace_root(kpptr_to_paddr(KERNEL_ROOT_PAGE_TABLE.as_ptr() as usize), 0);
+        super::set_gstage_root(
+            pptr!(lvl1pt as *mut PTE).to_paddr(),
+            super::get_hw_vmid(asid),
+        );
+    }
     ret
 }
-pub fn unmap_page_table(asid: asid_t, vptr: VPtr, pt: &mut PTE) {
+///清除`TLB`中属于`asid`的项，在`hypervisor`下为其`VMID`的 G-stage 项
+///
+/// Flush the translations of `asid`, under `hypervisor` the G-stage ones of its VMID. A guest
+/// vspace without a VMID has nothing in the TLB.
+#[inline]
+pub fn invalidate_tlb_by_asid(asid: asid_t) {
+    #[cfg(feature = "hypervisor")]
+    if let Some(vmid) = super::find_hw_vmid(asid) {
+        super::hfence_gvma_vmid_all(vmid);
+    }
+    #[cfg(not(feature = "hypervisor"))]
+    super::hw_asid_flush(asid);
+}
+
+///清除`TLB`中属于`asid`的虚拟地址`vptr`的项，在`hypervisor`下`vptr`为客户物理地址，清除其 G-stage 项
+///
+/// Flush the translation of `vptr` in `asid`. Under `hypervisor` `vptr` is a guest physical
+/// address, whose G-stage translation is flushed with `hfence.gvma`.
+#[inline]
+pub fn invalidate_tlb_by_asid_va(asid: asid_t, vptr: VPtr) {
+    #[cfg(feature = "hypervisor")]
+    if let Some(vmid) = super::find_hw_vmid(asid) {
+        super::hfence_gvma_gpa_vmid(vptr.raw(), vmid);
+    }
+    #[cfg(all(not(feature = "hypervisor"), target_arch = "riscv64"))]
+    unsafe {
+        core::arch::asm!("sfence.vma {0}, {1}", in(reg) vptr.raw(), in(reg) asid);
+    }
+}
+
+pub fn unmap_page_table(asid: asid_t, vptr: VPtr, pt: &mut PTE) -> Result<(), vaddr_error_t> {
     let target_pt = pt as *mut PTE;
+    check_user_vaddr(vptr)?;
     let find_ret = find_vspace_for_asid(asid);
     if find_ret.status != exception_t::EXCEPTION_NONE {
-        return;
+        return Ok(());
     }
     assert_ne!(find_ret.vspace_root.unwrap(), target_pt);
     let mut pt = find_ret.vspace_root.unwrap();
-    let mut ptSlot = unsafe { &mut *(pt.add(riscv_get_pt_index(vptr.raw(), 0))) };
+    let mut ptSlot = unsafe { &mut *(pt.add(vspace_get_pt_index(vptr.raw(), 0))) };
     let mut i = 0;
     while i < CONFIG_PT_LEVELS - 1 && pt != target_pt {
-        ptSlot = unsafe { &mut *(pt.add(riscv_get_pt_index(vptr.raw(), i))) };
+        ptSlot = unsafe { &mut *(pt.add(vspace_get_pt_index(vptr.raw(), i))) };
         if unlikely(ptSlot.is_pte_table()) {
-            return;
+            return Ok(());
         }
         pt = ptSlot.get_pte_from_ppn_mut() as *mut PTE;
         i += 1;
     }
 
     if pt != target_pt {
-        return;
+        return Ok(());
     }
     *ptSlot = PTE::new(0, PTEFlags::empty());
     sfence();
+    Ok(())
 }
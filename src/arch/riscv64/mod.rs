@@ -1,7 +1,13 @@
 mod asid;
 mod boot;
 mod device;
+#[cfg(feature = "hypervisor")]
+mod gstage;
 mod interface;
+#[cfg(feature = "riscv_svnapot")]
+mod napot;
+#[cfg(feature = "hypervisor")]
+mod nested;
 mod pagetable;
 mod pte;
 mod satp;
@@ -10,11 +16,23 @@ mod utils;
 pub use asid::*;
 pub use boot::*;
 pub use device::*;
-pub use interface::{set_vm_root, unmap_page_table};
+#[cfg(feature = "hypervisor")]
+pub use gstage::*;
+pub use interface::{
+    invalidate_tlb_by_asid, invalidate_tlb_by_asid_va, set_vm_root, unmap_page_table,
+};
+#[cfg(feature = "riscv_svnapot")]
+pub use napot::*;
+#[cfg(feature = "hypervisor")]
+pub use nested::*;
 pub use pagetable::{
-    activate_kernel_vspace, copyGlobalMappings, rust_map_kernel_window, unmap_page,
+    activate_kernel_vspace, copyGlobalMappings, reclaim_boot_text, rust_map_kernel_window,
+    unmap_page,
 };
-pub use pte::PTEFlags;
+pub(crate) use pte::lookup_vspace_slot;
+pub use pte::{pbmt_t, PTEFlags, PTE_SW_BITS, PTE_SW_BITS_SHIFT};
+#[cfg(feature = "access_tracking")]
+pub use satp::enable_hw_access_dirty;
 pub use satp::{set_vspace_root, sfence};
 pub use structures::*;
 pub use utils::*;
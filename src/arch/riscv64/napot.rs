@@ -0,0 +1,212 @@
+//! Svnapot 64 KiB 连续页
+//!
+//! Svnapot 64 KiB contiguous pages. A NAPOT group is 16 aligned level 3 entries mapping 64 KiB
+//! of aligned contiguous memory, all encoded alike with the N bit set and PPN[3:0] = 0b1000, so
+//! the TLB can hold a single entry for the whole group.
+use core::sync::atomic::Ordering;
+
+use rel4_arch::basic::{PAddr, VPtr};
+use sel4_common::sel4_config::SEL4_PAGE_BITS;
+
+use super::{lookup_vspace_slot, sfence};
+use crate::{asid_t, check_user_frame, map_error_t, PTEFlags, PageSize, PTE};
+
+/// 64 KiB NAPOT 页的大小位数
+///
+/// Size bits of a 64 KiB NAPOT page.
+pub const NAPOT_64K_BITS: usize = 16;
+/// 一个 NAPOT 组中的页表项数
+///
+/// Number of entries of a NAPOT group.
+pub const NAPOT_64K_ENTRIES: usize = bit!(NAPOT_64K_BITS - SEL4_PAGE_BITS);
+/// 64 KiB NAPOT 页表项中 PPN[3:0] 的编码
+///
+/// PPN[3:0] of a 64 KiB NAPOT entry.
+const NAPOT_64K_PPN_PATTERN: usize = 0b1000;
+const PPN_SHIFT: usize = 10;
+
+impl PTE {
+    #[inline]
+    pub fn is_napot(&self) -> bool {
+        self.0 & PTEFlags::N.bits() != 0
+    }
+
+    /// 映射 64 KiB 对齐起始页的叶子页表项对应的 NAPOT 编码
+    ///
+    /// The NAPOT encoding of the leaf mapping the first page of a 64 KiB aligned group.
+    #[inline]
+    fn napot_64k(&self) -> Self {
+        assert_eq!(self.get_ppn() & mask_bits!(4), 0);
+        PTE(self.0 | PTEFlags::N.bits() | (NAPOT_64K_PPN_PATTERN << PPN_SHIFT))
+    }
+
+    /// NAPOT 组映射的 64 KiB 的起始物理地址
+    ///
+    /// The start of the 64 KiB mapped by the NAPOT group of this entry.
+    #[inline]
+    fn napot_base(&self) -> PAddr {
+        paddr!((self.get_ppn() & !mask_bits!(4)) << SEL4_PAGE_BITS)
+    }
+
+    /// NAPOT 组中第`index`个页表项对应的普通 4 KiB 页表项
+    ///
+    /// The plain 4 KiB entry mapping page `index` of the NAPOT group of this entry.
+    #[inline]
+    fn napot_split(&self, index: usize) -> Self {
+        let pte = self.0 & !(PTEFlags::N.bits() | (mask_bits!(4) << PPN_SHIFT));
+        PTE(pte | (index << PPN_SHIFT))
+    }
+}
+
+/// `slot`所在的 NAPOT 组
+///
+/// The NAPOT group `slot` belongs to.
+#[inline]
+fn napot_group(slot: *mut PTE) -> &'static [PTE] {
+    let first = (slot as usize) & !(NAPOT_64K_ENTRIES * core::mem::size_of::<PTE>() - 1);
+    unsafe { core::slice::from_raw_parts(first as *const PTE, NAPOT_64K_ENTRIES) }
+}
+
+/// 将叶子页表项`pte`（如`PTE::make_user_pte`的结果）描述的 64 KiB 对齐帧以一个 NAPOT 组映射到`asid`
+/// 地址空间的`vptr`处
+///
+/// Map the 64 KiB aligned frame of the leaf `pte`, e.g. made by `PTE::make_user_pte`, at `vptr`
+/// of the vspace of `asid` as one NAPOT group, which is how `PageSize::Napot64K` frames are
+/// mapped, and record it in the reverse map with `reverse_map`. Fails at an invalid or misaligned
+/// address or when the reverse map is full. Otherwise the level 3 table has to exist and the 16
+/// entries have to be free, returns whether the frame was mapped.
+#[cfg_attr(not(feature = "reverse_map"), allow(unused_variables))]
+pub fn napot_map(
+    vspace_root: *mut PTE,
+    asid: asid_t,
+    vptr: VPtr,
+    pte: PTE,
+) -> Result<bool, map_error_t> {
+    check_user_frame(vptr, PageSize::Napot64K)?;
+    if pte.get_valid() == 0 || pte.is_pte_table() || pte.get_ppn() & mask_bits!(4) != 0 {
+        return Ok(false);
+    }
+    let lu_ret = lookup_vspace_slot(vspace_root, vptr);
+    if lu_ret.ptBitsLeft != SEL4_PAGE_BITS {
+        return Ok(false);
+    }
+    let group = napot_group(lu_ret.ptSlot);
+    if group.iter().any(|entry| entry.load().get_valid() != 0) {
+        return Ok(false);
+    }
+    #[cfg(feature = "reverse_map")]
+    crate::rmap_insert(pte.get_page_base_address(), asid, vptr, PageSize::Napot64K)?;
+    let napot = pte.napot_64k();
+    for entry in group {
+        entry.atomic().store(napot.0, Ordering::Release);
+    }
+    sfence();
+    Ok(true)
+}
+
+/// 解除`slot`所在的映射`frame`的 NAPOT 组，返回是否解除
+///
+/// Unmap the NAPOT group of `slot` if it maps `frame`, returns whether it was unmapped.
+pub(crate) fn napot_unmap(slot: *mut PTE, frame: PAddr) -> bool {
+    let first = unsafe { (*slot).load() };
+    if !first.is_napot() || first.is_pte_table() || first.napot_base().raw() != frame.raw() {
+        return false;
+    }
+    for entry in napot_group(slot) {
+        entry.atomic().store(0, Ordering::Release);
+    }
+    sfence();
+    true
+}
+
+/// 若`vptr`所在的 16 个 4 KiB 页表项映射了连续对齐的 64 KiB 且属性相同，将其合并为一个 NAPOT 组
+///
+/// Merge the 16 level 3 entries around `vptr` into a NAPOT group if they map 64 KiB of aligned
+/// contiguous memory with the same flags. Returns whether they were merged.
+pub fn napot_promote(vspace_root: *mut PTE, vptr: VPtr) -> bool {
+    let lu_ret = lookup_vspace_slot(vspace_root, vptr);
+    if lu_ret.ptBitsLeft != SEL4_PAGE_BITS {
+        return false;
+    }
+    let group = napot_group(lu_ret.ptSlot);
+    let first = group[0].load();
+    if first.get_valid() == 0
+        || first.is_pte_table()
+        || first.is_napot()
+        || first.get_ppn() & mask_bits!(4) != 0
+    {
+        return false;
+    }
+    let contiguous = group
+        .iter()
+        .enumerate()
+        .all(|(i, pte)| pte.load().0 == first.0 + (i << PPN_SHIFT));
+    if !contiguous {
+        return false;
+    }
+    let napot = first.napot_64k();
+    for pte in group {
+        pte.atomic().store(napot.0, Ordering::Release);
+    }
+    sfence();
+    true
+}
+
+/// 将`slot`所在的 NAPOT 组拆分为 16 个普通 4 KiB 页表项，保留各自的`A`、`D`位
+///
+/// Split the NAPOT group of `slot` back into 16 plain 4 KiB entries, each keeping its own
+/// A and D bits. Must be done before changing a single page of the group.
+pub fn napot_demote(slot: *mut PTE) {
+    if !unsafe { (*slot).load() }.is_napot() {
+        return;
+    }
+    for (i, pte) in napot_group(slot).iter().enumerate() {
+        let _ = pte
+            .atomic()
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |pte| {
+                PTE(pte).is_napot().then(|| PTE(pte).napot_split(i).0)
+            });
+    }
+    sfence();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FLAGS: usize = PTEFlags::V.bits() | PTEFlags::R.bits() | PTEFlags::U.bits();
+    /// A leaf mapping the 64 KiB aligned frame at 0x8123_0000.
+    const LEAF: PTE = PTE(((0x8123_0000 >> SEL4_PAGE_BITS) << PPN_SHIFT) | FLAGS);
+
+    #[test]
+    fn napot_encoding() {
+        let napot = LEAF.napot_64k();
+        assert!(napot.is_napot());
+        assert!(!LEAF.is_napot());
+        assert_eq!(napot.get_ppn() & mask_bits!(4), NAPOT_64K_PPN_PATTERN);
+        assert_eq!(napot.napot_base().raw(), 0x8123_0000);
+        assert_eq!(napot.0 & mask_bits!(PPN_SHIFT), FLAGS);
+    }
+
+    #[test]
+    fn napot_split_maps_each_page() {
+        let napot = LEAF.napot_64k();
+        for index in 0..NAPOT_64K_ENTRIES {
+            let pte = napot.napot_split(index);
+            assert!(!pte.is_napot());
+            assert_eq!(pte.get_ppn(), LEAF.get_ppn() + index);
+            assert_eq!(pte.0 & mask_bits!(PPN_SHIFT), FLAGS);
+        }
+    }
+
+    #[test]
+    fn napot_page_size() {
+        let size = PageSize::Napot64K;
+        assert_eq!(PageSize::from_cap_size(size.to_cap_size()), Some(size));
+        assert_eq!(size.size(), 64 * 1024);
+        assert_eq!(size.level(), PageSize::Small.level());
+        assert_eq!(PageSize::from_bits(NAPOT_64K_BITS), Some(size));
+        // A level 3 leaf is a small page, unless it is part of a NAPOT group.
+        assert_eq!(PageSize::from_level(size.level()), Some(PageSize::Small));
+    }
+}
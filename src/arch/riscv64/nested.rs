@@ -0,0 +1,97 @@
+//! 嵌套翻译中页表项的解码与页表几何参数，供`crate::nested`使用
+//!
+//! Descriptor decoding and table geometry used by the nested walker in `crate::nested`.
+use sel4_common::sel4_config::{CONFIG_PT_LEVELS, PT_INDEX_BITS, SEL4_PAGE_BITS};
+
+use super::GSTAGE_ROOT_INDEX_BITS;
+use crate::nested::{translation_stage_t, walk_entry_t, walk_geometry_t};
+use crate::PTE;
+
+/// G-stage 页表与宿主页表级数相同，根页表多 2 位索引
+///
+/// G-stage tables have as many levels as the host ones, with a root 2 bits wider.
+pub(crate) const S2_GEOMETRY: walk_geometry_t = walk_geometry_t {
+    levels: CONFIG_PT_LEVELS,
+    granule_bits: SEL4_PAGE_BITS,
+    index_bits: PT_INDEX_BITS,
+    root_index_bits: GSTAGE_ROOT_INDEX_BITS,
+    sign_extend: false,
+    output_bits: RISCV_OUTPUT_BITS,
+};
+
+/// 页表项中 44 位`PPN`对应的物理地址位数
+///
+/// Bits of the physical addresses held by the 44-bit PPN of an entry.
+const RISCV_OUTPUT_BITS: usize = 44 + SEL4_PAGE_BITS;
+
+impl walk_geometry_t {
+    ///由客户机的`vsatp`得到其页表的几何参数，`Bare`模式没有页表，不支持的模式返回`None`
+    ///
+    /// The geometry of the guest tables described by the guest's `vsatp`: Sv39, Sv48 or Sv57,
+    /// no levels with `Bare`, `None` for a reserved mode. The root is at `vsatp.PPN`.
+    pub fn from_vsatp(vsatp: usize) -> Option<Self> {
+        let levels = match vsatp >> 60 {
+            0 => 0,
+            8 => 3,
+            9 => 4,
+            10 => 5,
+            _ => return None,
+        };
+        Some(Self {
+            levels,
+            granule_bits: SEL4_PAGE_BITS,
+            index_bits: PT_INDEX_BITS,
+            root_index_bits: PT_INDEX_BITS,
+            sign_extend: true,
+            output_bits: RISCV_OUTPUT_BITS,
+        })
+    }
+}
+
+impl PTE {
+    ///解码第`level`级的页表项，最后一级的非叶子项无效
+    ///
+    /// Decode an entry found at `level` of a walk, both stages share the format.
+    pub(crate) fn walk_entry(
+        &self,
+        _stage: translation_stage_t,
+        geometry: &walk_geometry_t,
+        level: usize,
+    ) -> walk_entry_t {
+        let base = paddr!(self.get_ppn() << SEL4_PAGE_BITS);
+        if self.get_valid() == 0 {
+            walk_entry_t::Invalid
+        } else if !self.is_pte_table() {
+            walk_entry_t::Leaf(base)
+        } else if level < geometry.levels - 1 {
+            walk_entry_t::Table(base)
+        } else {
+            walk_entry_t::Invalid
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn geometry_from_vsatp() {
+        for (mode, levels) in [(8, 3), (9, 4), (10, 5)] {
+            let geometry = walk_geometry_t::from_vsatp((mode << 60) | 0x1234).unwrap();
+            let input_bits = SEL4_PAGE_BITS + PT_INDEX_BITS * levels;
+            assert_eq!(geometry.levels, levels);
+            assert_eq!(geometry.input_bits(), input_bits);
+            // Sign-extended addresses are in range, the others are not.
+            assert!(geometry.contains(mask_bits!(input_bits - 1)));
+            assert!(geometry.contains(!mask_bits!(input_bits - 1)));
+            assert!(!geometry.contains(bit!(input_bits - 1)));
+            assert_eq!(
+                geometry.index(bit!(input_bits - 2), 0),
+                bit!(PT_INDEX_BITS - 2)
+            );
+        }
+        assert_eq!(walk_geometry_t::from_vsatp(0).map(|g| g.levels), Some(0));
+        assert_eq!(walk_geometry_t::from_vsatp(1 << 60), None);
+    }
+}
@@ -1,15 +1,19 @@
-use crate::{asid_t, find_vspace_for_asid, sfence, PTE};
-use rel4_arch::basic::{PPtr, VPtr};
+use crate::kernel_image::KERNEL_IMAGE_BOUNDARIES;
+use crate::{
+    asid_t, check_user_frame, find_vspace_for_asid, sfence, unmap_error_t, KernelImageLayout,
+    KernelSection, PageSize, ReclaimedRegions, PTE,
+};
+use core::mem::size_of;
+use rel4_arch::basic::{PPtr, PRegion, VPtr};
 use rel4_utils::no_lock::NoLock;
 use sel4_common::{
     arch::config::{
-        KERNEL_ELF_BASE, KERNEL_ELF_PADDR_BASE, PADDR_BASE, PADDR_TOP, PPTR_BASE, PPTR_BASE_OFFSET,
-        PPTR_TOP,
+        KDEV_BASE, KERNEL_ELF_BASE, KERNEL_ELF_PADDR_BASE, PADDR_BASE, PADDR_TOP, PPTR_BASE,
+        PPTR_BASE_OFFSET, PPTR_TOP,
     },
     sel4_config::{PT_INDEX_BITS, SEL4_PAGE_BITS},
     structures::exception_t,
-    structures_gen::lookup_fault,
-    utils::pageBitsForSize,
+    utils::convert_to_mut_slice,
 };
 
 use super::{
@@ -29,10 +33,33 @@ pub(crate) static KERNEL_ROOT_PAGE_TABLE: NoLock<[PTE; bit!(PT_INDEX_BITS)]> =
 pub(crate) static KERNEL_IMAGE_LEVEL2_PT: NoLock<[PTE; bit!(PT_INDEX_BITS)]> =
     NoLock::new([PTE::pte_invalid(); bit!(PT_INDEX_BITS)]);
 
+///内核镜像跨越段边界的`2MiB`区域使用的三级页表，每个段边界最多需要一个
+#[link_section = ".page_table"]
+#[export_name = "kernel_image_level3_pts"]
+pub(crate) static KERNEL_IMAGE_LEVEL3_PTS: NoLock<
+    [[PTE; bit!(PT_INDEX_BITS)]; KERNEL_IMAGE_BOUNDARIES],
+> = NoLock::new([[PTE::pte_invalid(); bit!(PT_INDEX_BITS)]; KERNEL_IMAGE_BOUNDARIES]);
+
+///内核设备使用的二级页表，挂在根页表`KDEV_BASE`对应的表项下
+#[link_section = ".page_table"]
+#[export_name = "kernel_device_level2_pt"]
+pub(crate) static KERNEL_DEVICE_LEVEL2_PT: NoLock<[PTE; bit!(PT_INDEX_BITS)]> =
+    NoLock::new([PTE::pte_invalid(); bit!(PT_INDEX_BITS)]);
+
+///内核设备使用的三级页表，以`4KiB`粒度映射`KDEV_BASE`开始的`2MiB`，
+/// 对应`aarch64`的`armKSGlobalKernelPT`
+#[link_section = ".page_table"]
+#[export_name = "kernel_device_level3_pt"]
+pub(crate) static KERNEL_DEVICE_LEVEL3_PT: NoLock<[PTE; bit!(PT_INDEX_BITS)]> =
+    NoLock::new([PTE::pte_invalid(); bit!(PT_INDEX_BITS)]);
+
 /// 构建`reL4`的内核页表,主要完成了`PSpace`和`KERNEL ELF`两段虚拟地址空间的映射
 ///
 /// 其中`PSpace`是对整个物理地址空间的线性映射，`KERNEL ELF`是对内核代码的再一次映射
 ///
+/// 内核镜像按`W^X`映射：`.text`可读可执行，`.rodata`只读，`.data/.bss`以及`PSpace`可读写不可执行，
+/// 完全落在同一个段中的`2MiB`区域使用大页，跨越段边界的区域使用`4KiB`页
+///
 /// reL4的地址空间如下图所示（来源：seL4/include/arch/riscv/arch/64/mode/hardware.h），左侧为虚拟地址空间，右侧为物理地址空间：
 ///
 /// ```
@@ -77,18 +104,26 @@ pub(crate) static KERNEL_IMAGE_LEVEL2_PT: NoLock<[PTE; bit!(PT_INDEX_BITS)]> =
 ///
 #[no_mangle]
 pub fn rust_map_kernel_window() {
+    let layout = KernelImageLayout::current();
     // 物理地址到内核地址空间的直接映射，用`1GB`大页的方式映射
     for (pptr, paddr) in (PPTR_BASE..PPTR_TOP)
         .step_by(riscv_get_lvl_pgsize(0))
         .zip((PADDR_BASE..PADDR_TOP).step_by(riscv_get_lvl_pgsize(0)))
     {
         KERNEL_ROOT_PAGE_TABLE.no_lock()[riscv_get_pt_index(pptr, 0)] =
-            PTE::pte_next_table(paddr!(paddr), true);
+            PTE::make_kernel_image_pte(paddr!(paddr), KernelSection::Data);
     }
 
     let mut pptr = pptr!(KERNEL_ELF_BASE).align_down(riscv_get_lvl_pgsize_bits(0));
     let mut paddr = paddr!(KERNEL_ELF_PADDR_BASE).align_down(riscv_get_lvl_pgsize_bits(0));
-    // 将根页表`KERNEL_ELF_PADDR_BASE`和`KERNEL_ELF_BASE`处的页表项改为使用`kernel_image_level2_pt`映射
+    // 将根页表`KERNEL_ELF_PADDR_BASE`和`KERNEL_ELF_BASE`处的页表项改为使用`kernel_image_level2_pt`映射，
+    // 内核镜像在`PSpace`中的别名因此与`KERNEL_ELF_BASE`处的映射使用相同的段权限，
+    // 不能通过`PSpace`写`.text`。共用页表要求两者在`1GiB`内的偏移相同
+    assert_eq!(
+        KERNEL_ELF_BASE & mask_bits!(riscv_get_lvl_pgsize_bits(0)),
+        KERNEL_ELF_PADDR_BASE & mask_bits!(riscv_get_lvl_pgsize_bits(0)),
+        "the kernel image alias in PSpace cannot share the kernel image tables"
+    );
     KERNEL_ROOT_PAGE_TABLE.no_lock()
         [riscv_get_pt_index(KERNEL_ELF_PADDR_BASE + PPTR_BASE_OFFSET, 0)] = PTE::pte_next_table(
         kpptr_to_paddr(KERNEL_IMAGE_LEVEL2_PT.as_ptr() as usize),
@@ -101,21 +136,110 @@ pub fn rust_map_kernel_window() {
     );
 
     let mut index = 0;
+    let mut level3_used = 0;
     // 做了 `0xFFFF_FFFF_8400_0000(KERNEL_ELF_BASE)~0xFFFF_FFFF_C4000_0000(KDEV_BASE)`到`0x8400_0000~0xC400_0000`的地址映射。
     while pptr.raw() < PPTR_TOP + riscv_get_lvl_pgsize(0) {
-        KERNEL_IMAGE_LEVEL2_PT.no_lock()[index] = PTE::pte_next_table(paddr, true);
+        let chunk_end = pptr.raw() + riscv_get_lvl_pgsize(1);
+        KERNEL_IMAGE_LEVEL2_PT.no_lock()[index] =
+            match layout.uniform_section(pptr.raw(), chunk_end) {
+                Some(section) => PTE::make_kernel_image_pte(paddr, section),
+                None => {
+                    // 该`2MiB`区域跨越了段边界，拆分为`4KiB`页
+                    assert!(level3_used < KERNEL_IMAGE_BOUNDARIES);
+                    let level3_pt = &mut KERNEL_IMAGE_LEVEL3_PTS.no_lock()[level3_used];
+                    level3_used += 1;
+                    for (i, slot) in level3_pt.iter_mut().enumerate() {
+                        let offset = i * riscv_get_lvl_pgsize(2);
+                        *slot = PTE::make_kernel_image_pte(
+                            paddr + offset,
+                            layout.section_of(pptr.raw() + offset),
+                        );
+                    }
+                    PTE::pte_next_table(kpptr_to_paddr(level3_pt.as_ptr() as usize), false)
+                }
+            };
         pptr += riscv_get_lvl_pgsize(1);
         paddr += riscv_get_lvl_pgsize(1);
         index += 1;
     }
+
+    // 内核设备区域使用`kernel_device_level2_pt`和`kernel_device_level3_pt`映射，
+    // 使得设备可以按`4KiB`粒度映射，不会覆盖相邻的设备
+    KERNEL_ROOT_PAGE_TABLE.no_lock()[riscv_get_pt_index(KDEV_BASE, 0)] = PTE::pte_next_table(
+        kpptr_to_paddr(KERNEL_DEVICE_LEVEL2_PT.as_ptr() as usize),
+        false,
+    );
+    KERNEL_DEVICE_LEVEL2_PT.no_lock()[riscv_get_pt_index(KDEV_BASE, 1)] = PTE::pte_next_table(
+        kpptr_to_paddr(KERNEL_DEVICE_LEVEL3_PT.as_ptr() as usize),
+        false,
+    );
     map_kernel_devices();
 }
 
+/// 启动完成后回收`.boot.text`：内核窗口同时也是`untyped`分配器使用的物理内存映射，
+/// 因此将其重新映射为可读写不可执行而不是取消映射，只映射数据的三级页表替换为`2MiB`大页。
+/// 返回被释放的物理地址区间（`.boot.text`以及这些三级页表），可以交给`untyped`分配器。
+///
+/// Give `.boot.text` back once booting is done: remap it RW-NX, replace the level 3 tables
+/// left with data only by 2 MiB leaves, and return the freed physical regions for the untyped
+/// allocator.
+pub fn reclaim_boot_text() -> ReclaimedRegions {
+    let layout = KernelImageLayout::current();
+    let (start, end) = layout.boot_text_pages();
+    let mut reclaimed = ReclaimedRegions::new();
+    if start == end {
+        return reclaimed;
+    }
+    reclaimed.push(PRegion::new(kpptr_to_paddr(start), kpptr_to_paddr(end)));
+
+    let mut vaddr = start;
+    while vaddr < end {
+        let level2_slot = &mut KERNEL_IMAGE_LEVEL2_PT.no_lock()[riscv_get_pt_index(vaddr, 1)];
+        if level2_slot.is_pte_table() {
+            let level3_pt = convert_to_mut_slice::<PTE>(
+                level2_slot.get_pte_from_ppn_mut() as *mut PTE as usize,
+                bit!(PT_INDEX_BITS),
+            );
+            level3_pt[riscv_get_pt_index(vaddr, 2)] =
+                PTE::make_kernel_image_pte(kpptr_to_paddr(vaddr), KernelSection::Data);
+            vaddr += riscv_get_lvl_pgsize(2);
+        } else {
+            // 只有完全落在`.boot.text`中的`2MiB`区域才会使用大页
+            *level2_slot = PTE::make_kernel_image_pte(kpptr_to_paddr(vaddr), KernelSection::Data);
+            vaddr += riscv_get_lvl_pgsize(1);
+        }
+    }
+
+    let mut block = start & !mask_bits!(riscv_get_lvl_pgsize_bits(1));
+    while block < end {
+        let level2_slot = &mut KERNEL_IMAGE_LEVEL2_PT.no_lock()[riscv_get_pt_index(block, 1)];
+        if level2_slot.is_pte_table()
+            && layout.reclaimed_all_data(block, block + riscv_get_lvl_pgsize(1))
+        {
+            let level3_pt = level2_slot.get_page_base_address();
+            *level2_slot = PTE::pte_invalid();
+            sfence();
+            *level2_slot = PTE::make_kernel_image_pte(kpptr_to_paddr(block), KernelSection::Data);
+            reclaimed.push(PRegion::new(
+                level3_pt,
+                level3_pt + bit!(PT_INDEX_BITS) * size_of::<PTE>(),
+            ));
+        }
+        block += riscv_get_lvl_pgsize(1);
+    }
+    sfence();
+    reclaimed
+}
+
 /// 激活内核页表，将`satp`的值设置为内核页表根页表地址
 ///
 /// Activate kernel vspace, assign kernel root page table's value to satp.
 #[inline]
 pub fn activate_kernel_vspace() {
+    #[cfg(feature = "access_tracking")]
+    crate::access::set_hw_access_dirty(super::satp::enable_hw_access_dirty());
+    #[cfg(feature = "hypervisor")]
+    super::vmid_init();
     set_vspace_root(kpptr_to_paddr(KERNEL_ROOT_PAGE_TABLE.as_ptr() as usize), 0);
 }
 
@@ -123,13 +247,19 @@ pub fn activate_kernel_vspace() {
 ///
 /// Copy the whole kernel page table into a new page table.
 /// when create a new process, a new page table will be alloced to the new process.
+/// Under `hypervisor` the new root is a `VSPACE_ROOT_BITS` G-stage root, which is cleared instead.
 #[no_mangle]
 pub fn copyGlobalMappings(Lvl1pt: PPtr) {
-    let mut i: usize = riscv_get_pt_index(0x80000000, 0);
-    while i < bit!(PT_INDEX_BITS) {
-        let newLvl1pt = (Lvl1pt + i * 8).get_mut_ref();
-        *newLvl1pt = KERNEL_ROOT_PAGE_TABLE.no_lock()[i];
-        i += 1;
+    #[cfg(feature = "hypervisor")]
+    super::gstage_root_init(Lvl1pt);
+    #[cfg(not(feature = "hypervisor"))]
+    {
+        let mut i: usize = riscv_get_pt_index(0x80000000, 0);
+        while i < bit!(PT_INDEX_BITS) {
+            let newLvl1pt = (Lvl1pt + i * 8).get_mut_ref();
+            *newLvl1pt = KERNEL_ROOT_PAGE_TABLE.no_lock()[i];
+            i += 1;
+        }
     }
 }
 
@@ -146,7 +276,7 @@ pub fn unmap_page(
     asid: asid_t,
     vptr: VPtr,
     pptr: PPtr,
-) -> Result<(), lookup_fault> {
+) -> Result<(), unmap_error_t> {
     /*
         let find_ret = find_vspace_for_asid(asid);
         if find_ret.status != exception_t::EXCEPTION_NONE {
@@ -179,13 +309,30 @@ pub fn unmap_page(
     */
     let find_ret = find_vspace_for_asid(asid);
     if find_ret.status != exception_t::EXCEPTION_NONE {
-        return Err(find_ret.lookup_fault.unwrap());
+        return Err(unmap_error_t::Lookup(find_ret.lookup_fault.unwrap()));
     }
+    let page_size = match PageSize::from_cap_size(page_size) {
+        Some(page_size) => page_size,
+        None => return Ok(()),
+    };
+    check_user_frame(vptr, page_size)?;
     // TODO: Unify lookup_pt_slot
     let lu_ret = unsafe { (*find_ret.vspace_root.unwrap()).lookup_pt_slot(vptr) };
-    if lu_ret.ptBitsLeft != pageBitsForSize(page_size) {
+    if lu_ret.ptBitsLeft != riscv_get_lvl_pgsize_bits(page_size.level()) {
+        return Ok(());
+    }
+    // 64 KiB 的 NAPOT 页整组解除
+    #[cfg(feature = "riscv_svnapot")]
+    if page_size == PageSize::Napot64K {
+        if super::napot_unmap(lu_ret.ptSlot, pptr.to_paddr()) {
+            #[cfg(feature = "reverse_map")]
+            crate::rmap_remove(pptr.to_paddr(), asid, vptr);
+        }
         return Ok(());
     }
+    // 只解除 NAPOT 组中的一页前，先将组拆分为普通页表项
+    #[cfg(feature = "riscv_svnapot")]
+    super::napot_demote(lu_ret.ptSlot);
 
     let slot = unsafe { &(*lu_ret.ptSlot) };
 
@@ -198,5 +345,7 @@ pub fn unmap_page(
 
     *pptr!(lu_ret.ptSlot).get_mut_ref::<usize>() = 0;
     sfence();
+    #[cfg(feature = "reverse_map")]
+    crate::rmap_remove(pptr.to_paddr(), asid, vptr);
     Ok(())
 }
@@ -1,5 +1,6 @@
 use bitflags::bitflags;
 use core::intrinsics::unlikely;
+use core::sync::atomic::Ordering;
 use rel4_arch::basic::{PAddr, VPtr};
 use sel4_common::{
     arch::{riscv_get_read_from_vm_rights, riscv_get_write_from_vm_rights, vm_rights_t},
@@ -7,11 +8,23 @@ use sel4_common::{
     structures::exception_t,
 };
 
+#[cfg(feature = "cow")]
+use crate::pte_sw_bit_t;
 use crate::{
-    arch::riscv64::{sfence, utils::riscv_get_pt_index},
-    asid_t, find_vspace_for_asid, lookupPTSlot_ret_t, PTE,
+    arch::riscv64::{sfence, utils::vspace_get_pt_index},
+    asid_t, check_user_frame, check_user_vaddr, find_vspace_for_asid, lookupPTSlot_ret_t,
+    map_error_t, vaddr_error_t, vm_attributes_t, KernelSection, PageSize, PTE,
 };
 
+/// 第一个软件保留位（`RSW`）
+///
+/// First software-reserved bit (`RSW`).
+pub const PTE_SW_BITS_SHIFT: usize = 8;
+/// 软件保留位`RSW`为第 8、9 位
+///
+/// Software-reserved bits, `RSW` is bits 8-9.
+pub const PTE_SW_BITS: usize = 2;
+
 bitflags! {
     #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
     pub struct PTEFlags: usize {
@@ -23,6 +36,18 @@ bitflags! {
         const G = bit!(5);
         const A = bit!(6);
         const D = bit!(7);
+        /// Svpbmt 内存类型：不可缓存的主存
+        ///
+        /// Svpbmt memory type: non-cacheable, idempotent, weakly-ordered main memory.
+        const PBMT_NC = bit!(61);
+        /// Svpbmt 内存类型：设备 I/O
+        ///
+        /// Svpbmt memory type: non-cacheable, non-idempotent, strongly-ordered I/O.
+        const PBMT_IO = bit!(62);
+        /// Svnapot：该叶子属于一个自然对齐的 2 的幂大小的连续区域
+        ///
+        /// Svnapot: the leaf is part of a naturally aligned power-of-two contiguous range.
+        const N = bit!(63);
 
         const VRWX  = Self::V.bits() | Self::R.bits() | Self::W.bits() | Self::X.bits();
         const ADUVRX = Self::A.bits() | Self::D.bits() | Self::U.bits() | Self::V.bits() | Self::R.bits() | Self::X.bits();
@@ -32,6 +57,34 @@ bitflags! {
     }
 }
 
+/// Svpbmt 定义的页面内存类型，未开启`riscv_svpbmt`时全部使用 PMA
+///
+/// Page-based memory types of Svpbmt. Without the `riscv_svpbmt` feature the PBMT bits are
+/// reserved, so every type is left to the PMAs.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum pbmt_t {
+    /// 由物理内存属性（PMA）决定
+    ///
+    /// Use the physical memory attributes of the region.
+    PMA = 0,
+    NC = 1,
+    IO = 2,
+}
+
+impl pbmt_t {
+    #[inline]
+    pub fn flags(&self) -> PTEFlags {
+        if !cfg!(feature = "riscv_svpbmt") {
+            return PTEFlags::empty();
+        }
+        match self {
+            pbmt_t::PMA => PTEFlags::empty(),
+            pbmt_t::NC => PTEFlags::PBMT_NC,
+            pbmt_t::IO => PTEFlags::PBMT_IO,
+        }
+    }
+}
+
 impl From<usize> for PTE {
     fn from(value: usize) -> Self {
         Self(value)
@@ -44,15 +97,24 @@ impl PTE {
         Self(flags.bits() | (ppn << 10))
     }
 
-    /// 创建一个用户使用的页表项（`Global=0`、`User=1`）
+    /// 创建一个用户使用的页表项（`Global=0`、`User=1`），可执行性与 Svpbmt 内存类型由`attr`决定，
+    /// 由`map_user_leaf`检查地址后映射
+    ///
+    /// Create a user entry, its executability and Svpbmt memory type come from `attr`. Map it with
+    /// `map_user_leaf`, which checks the address.
     #[inline]
-    pub fn make_user_pte(paddr: PAddr, executable: bool, vm_rights: vm_rights_t) -> Self {
+    pub fn make_user_pte(paddr: PAddr, vm_rights: vm_rights_t, attr: vm_attributes_t) -> Self {
+        let executable = !attr.get_riscv_execute_never();
         let write = riscv_get_write_from_vm_rights(&vm_rights);
         let read = riscv_get_read_from_vm_rights(&vm_rights);
         if !executable && !read && !write {
             return Self::pte_invalid();
         }
-        let mut flag = PTEFlags::V | PTEFlags::D | PTEFlags::A | PTEFlags::U;
+        let mut flag = PTEFlags::V | PTEFlags::U | attr.get_riscv_memory_type().flags();
+        // 开启访问追踪时，页表项创建时`A`、`D`为 0
+        if !cfg!(feature = "access_tracking") {
+            flag |= PTEFlags::D | PTEFlags::A;
+        }
         if executable {
             flag |= PTEFlags::X;
         }
@@ -65,6 +127,27 @@ impl PTE {
         Self::new(paddr.raw() >> SEL4_PAGE_BITS, flag)
     }
 
+    ///将用户叶子页表项`pte`映射到`asid`地址空间中`vaddr`处的本页表项（`lookup_pt_slot`的结果），
+    ///地址不合法、未对齐或反向映射已满时不做映射
+    ///
+    /// Map the user leaf `pte` of `page_size` at `vaddr` of the vspace of `asid` in this slot, as
+    /// found by `lookup_pt_slot`, and record it in the reverse map with `reverse_map`. Nothing is
+    /// mapped at an invalid or misaligned address, or when the reverse map is full.
+    pub fn map_user_leaf(
+        &mut self,
+        pte: PTE,
+        page_size: PageSize,
+        asid: asid_t,
+        vaddr: VPtr,
+    ) -> Result<(), map_error_t> {
+        check_user_frame(vaddr, page_size)?;
+        #[cfg(feature = "reverse_map")]
+        crate::rmap::rmap_replace(self.load(), pte, asid, vaddr, page_size)?;
+        self.split_group(asid, vaddr, page_size.bits());
+        self.update(pte);
+        Ok(())
+    }
+
     ///创建内核态页表项（`Global=1`、`User=0`）
     #[inline]
     pub fn pte_next_table(phys_addr: PAddr, is_leaf: bool) -> Self {
@@ -77,36 +160,97 @@ impl PTE {
         Self::new(ppn, flag)
     }
 
+    ///创建内核窗口页表项（`Global=1`、`User=0`），权限由所在的内核段决定：
+    /// `.boot.text`和`.text`可读可执行，`.rodata`只读，其余可读写不可执行
+    ///
+    /// Create a leaf of the kernel window with the permissions of `section`.
+    #[inline]
+    pub fn make_kernel_image_pte(phys_addr: PAddr, section: KernelSection) -> Self {
+        let mut flag = PTEFlags::V | PTEFlags::G | PTEFlags::A | PTEFlags::D | PTEFlags::R;
+        match section {
+            KernelSection::BootText | KernelSection::Text => flag |= PTEFlags::X,
+            KernelSection::RoData => {}
+            KernelSection::Data => flag |= PTEFlags::W,
+        }
+        Self::new(phys_addr.raw() >> SEL4_PAGE_BITS, flag)
+    }
+
+    ///创建内核设备页表项（`Global=1`、`User=0`、不可执行、内存类型为`memory_type`），只有`VMReadOnly`不可写
+    ///
+    /// Create a leaf for a kernel device, never executable and of the given memory type.
+    #[inline]
+    pub fn make_kernel_device_pte(
+        phys_addr: PAddr,
+        vm_rights: vm_rights_t,
+        memory_type: pbmt_t,
+    ) -> Self {
+        let mut flag = PTEFlags::V | PTEFlags::G | PTEFlags::A | PTEFlags::D | PTEFlags::R;
+        flag |= memory_type.flags();
+        if !matches!(vm_rights, vm_rights_t::VMReadOnly) {
+            flag |= PTEFlags::W;
+        }
+        Self::new(phys_addr.raw() >> SEL4_PAGE_BITS, flag)
+    }
+
     #[inline]
     pub fn update(&mut self, pte: Self) {
-        *self = pte;
+        self.prepare_update();
+        self.atomic().store(pte.0, Ordering::Release);
+        self.sync_update();
+    }
+
+    ///修改页表项前的准备：拆分其所在的 NAPOT 组
+    ///
+    /// Split the NAPOT group of this entry before it is modified.
+    #[inline]
+    pub(crate) fn prepare_update(&self) {
+        #[cfg(feature = "riscv_svnapot")]
+        super::napot_demote(self as *const PTE as *mut PTE);
+    }
+
+    ///修改`asid`地址空间中覆盖`vaddr`的叶子页表项前的准备，riscv 上与`prepare_update`相同
+    ///
+    /// Split the group of this leaf of the vspace of `asid` before it is modified, the same as
+    /// `prepare_update` on riscv.
+    #[inline]
+    pub(crate) fn split_group(&self, _asid: asid_t, _vaddr: VPtr, _size_bits: usize) {
+        self.prepare_update();
+    }
+
+    ///使页表项的修改对硬件页表遍历可见
+    ///
+    /// Make a change of the entry visible to the table walker.
+    #[inline]
+    pub(crate) fn sync_update(&self) {
         sfence();
     }
 
-    pub fn unmap_page_table(&mut self, asid: asid_t, vptr: VPtr) {
+    pub fn unmap_page_table(&mut self, asid: asid_t, vptr: VPtr) -> Result<(), vaddr_error_t> {
         let target_pt = self as *mut PTE;
+        check_user_vaddr(vptr)?;
         let find_ret = find_vspace_for_asid(asid);
         if find_ret.status != exception_t::EXCEPTION_NONE {
-            return;
+            return Ok(());
         }
         assert_ne!(find_ret.vspace_root.unwrap(), target_pt);
         let mut pt = find_ret.vspace_root.unwrap();
-        let mut ptSlot = unsafe { &mut *(pt.add(riscv_get_pt_index(vptr.raw(), 0))) };
+        let mut ptSlot = unsafe { &mut *(pt.add(vspace_get_pt_index(vptr.raw(), 0))) };
         let mut i = 0;
         while i < CONFIG_PT_LEVELS - 1 && pt != target_pt {
-            ptSlot = unsafe { &mut *(pt.add(riscv_get_pt_index(vptr.raw(), i))) };
+            ptSlot = unsafe { &mut *(pt.add(vspace_get_pt_index(vptr.raw(), i))) };
             if unlikely(ptSlot.is_pte_table()) {
-                return;
+                return Ok(());
             }
             pt = ptSlot.get_pte_from_ppn_mut() as *mut PTE;
             i += 1;
         }
 
         if pt != target_pt {
-            return;
+            return Ok(());
         }
         *ptSlot = PTE::new(0, PTEFlags::empty());
         sfence();
+        Ok(())
     }
 
     #[inline]
@@ -145,6 +289,11 @@ impl PTE {
         (self.0 & 0x3f_ffff_ffff_fc00usize) >> 10
     }
 
+    #[inline]
+    pub fn get_page_base_address(&self) -> PAddr {
+        paddr!(self.get_ppn() << SEL4_PAGE_BITS)
+    }
+
     #[inline]
     pub fn get_execute(&self) -> usize {
         (self.0 & 0x8usize) >> 3
@@ -160,18 +309,93 @@ impl PTE {
         (self.0 & 0x2usize) >> 1
     }
 
+    #[inline]
+    pub(crate) fn is_leaf(&self) -> bool {
+        self.get_valid() != 0 && !self.is_pte_table()
+    }
+
+    ///叶子页表项自上次清除后是否被访问过
+    ///
+    /// Whether the leaf was accessed since its bits were last cleared.
+    #[cfg(feature = "access_tracking")]
+    pub(crate) fn is_accessed(&self) -> bool {
+        self.is_leaf() && self.0 & PTEFlags::A.bits() != 0
+    }
+
+    ///叶子页表项自上次清除后是否被写过
+    ///
+    /// Whether the leaf was written since its bits were last cleared.
+    #[cfg(feature = "access_tracking")]
+    pub(crate) fn is_dirty(&self) -> bool {
+        self.is_leaf() && self.0 & PTEFlags::D.bits() != 0
+    }
+
+    ///清除`A`、`D`位后的叶子页表项，未修改时为`None`
+    ///
+    /// The leaf with the A and D bits clear, `None` if unchanged.
+    #[cfg(feature = "access_tracking")]
+    pub(crate) fn accessed_dirty_cleared(&self) -> Option<Self> {
+        let ad = PTEFlags::A.bits() | PTEFlags::D.bits();
+        if !self.is_leaf() || self.0 & ad == 0 {
+            return None;
+        }
+        Some(PTE(self.0 & !ad))
+    }
+
+    ///处理因`A`或`D`为 0 引起的页错误后的叶子页表项，错误不是由访问追踪引起时为`None`
+    ///
+    /// The leaf resolving a page fault caused by a clear A or D bit, `None` if the fault does
+    /// not come from access tracking.
+    #[cfg(feature = "access_tracking")]
+    pub(crate) fn accessed_marked(&self, write: bool) -> Option<Self> {
+        if !self.is_leaf() || (write && self.get_write() == 0) {
+            return None;
+        }
+        let mut pte = self.0 | PTEFlags::A.bits();
+        if write {
+            pte |= PTEFlags::D.bits();
+        }
+        (pte != self.0).then_some(PTE(pte))
+    }
+
+    ///叶子页表项是否为写时复制共享
+    ///
+    /// Whether the leaf is shared copy-on-write.
+    #[cfg(feature = "cow")]
+    pub(crate) fn is_cow(&self) -> bool {
+        self.is_leaf() && self.get_sw_bit(pte_sw_bit_t::Cow)
+    }
+
+    ///可写叶子页表项改为只读写时复制后的页表项，未修改时为`None`
+    ///
+    /// The writable leaf made read-only copy-on-write, `None` if unchanged.
+    #[cfg(feature = "cow")]
+    pub(crate) fn cow_protected(&self) -> Option<Self> {
+        if !self.is_leaf() || self.get_write() == 0 {
+            return None;
+        }
+        Some(PTE(self.0 & !PTEFlags::W.bits()).with_sw_bit(pte_sw_bit_t::Cow, true))
+    }
+
+    ///写时复制叶子页表项重新映射到私有副本`frame`并可写后的页表项
+    ///
+    /// The copy-on-write leaf remapped writable to its private copy at `frame`. Only the PPN
+    /// is replaced, the `PBMT` and `N` bits are kept.
+    #[cfg(feature = "cow")]
+    pub(crate) fn cow_broken(&self, frame: PAddr) -> Self {
+        let ppn_mask = mask_bits!(44) << 10;
+        let flags = (self.with_sw_bit(pte_sw_bit_t::Cow, false).0 & !ppn_mask)
+            | (PTEFlags::W | PTEFlags::A | PTEFlags::D).bits();
+        PTE(flags | (((frame.raw() >> SEL4_PAGE_BITS) << 10) & ppn_mask))
+    }
+
     ///用于记录某个虚拟地址`vptr`对应的pte表项在内存中的位置
     pub fn lookup_pt_slot(&mut self, vptr: VPtr) -> lookupPTSlot_ret_t {
         let mut level = CONFIG_PT_LEVELS - 1;
         let mut pt = self as *mut PTE;
         let mut ret = lookupPTSlot_ret_t {
             ptBitsLeft: PT_INDEX_BITS * level + SEL4_PAGE_BITS,
-            ptSlot: unsafe {
-                pt.add(
-                    (vptr.raw() >> (PT_INDEX_BITS * level + SEL4_PAGE_BITS))
-                        & mask_bits!(PT_INDEX_BITS),
-                )
-            },
+            ptSlot: unsafe { pt.add(vspace_get_pt_index(vptr.raw(), 0)) },
         };
 
         while unsafe { (*ret.ptSlot).is_pte_table() } && level > 0 {
@@ -184,3 +408,11 @@ impl PTE {
         ret
     }
 }
+
+///查找`vptr`在根页表为`vspace_root`的地址空间中对应的页表项
+///
+/// Find the slot of `vptr` in the vspace whose root table is `vspace_root`.
+#[inline]
+pub(crate) fn lookup_vspace_slot(vspace_root: *mut PTE, vptr: VPtr) -> lookupPTSlot_ret_t {
+    unsafe { (*vspace_root).lookup_pt_slot(vptr) }
+}
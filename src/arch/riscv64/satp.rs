@@ -54,6 +54,46 @@ pub fn sfence() {
     }
 }
 
+/// SBI FWFT 扩展号、`SET`功能号与`PTE_AD_HW_UPDATING`特性号
+#[cfg(feature = "access_tracking")]
+const SBI_EXT_FWFT: usize = 0x4657_4654;
+#[cfg(feature = "access_tracking")]
+const SBI_FWFT_SET: usize = 0;
+#[cfg(feature = "access_tracking")]
+const SBI_FWFT_PTE_AD_HW_UPDATING: usize = 4;
+
+///通过 SBI FWFT 扩展开启 Svadu，由硬件更新`A`、`D`位，返回是否开启成功
+///
+/// Enable Svadu through the SBI FWFT extension, returns whether A and D are updated by
+/// hardware. Under `hypervisor` the G-stage updates are enabled in `henvcfg.ADUE` too.
+#[cfg(feature = "access_tracking")]
+pub fn enable_hw_access_dirty() -> bool {
+    #[cfg(target_arch = "riscv64")]
+    {
+        let error: isize;
+        unsafe {
+            core::arch::asm!(
+                "ecall",
+                inlateout("a0") SBI_FWFT_PTE_AD_HW_UPDATING as isize => error,
+                inlateout("a1") 1usize => _,
+                in("a2") 0usize,
+                in("a6") SBI_FWFT_SET,
+                in("a7") SBI_EXT_FWFT,
+            );
+        }
+        if error != 0 {
+            return false;
+        }
+        #[cfg(feature = "hypervisor")]
+        unsafe {
+            core::arch::asm!("csrs 0x60a, {0}", in(reg) bit!(61));
+        }
+        true
+    }
+    #[cfg(not(target_arch = "riscv64"))]
+    false
+}
+
 ///设置页表，创建一个新的satp的值，然后将其写入satp寄存器
 ///
 /// Assign addr to satp.
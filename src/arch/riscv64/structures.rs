@@ -1,7 +1,44 @@
 use rel4_arch::basic::PPtr;
 use sel4_common::{sel4_config::ASID_LOW_BITS, utils::convert_to_option_mut_type_ref};
 
-use crate::PTE;
+use super::pbmt_t;
+use crate::{vm_attributes_t, PTE};
+
+/// `riscv`下`vm_attributes_t`的含义：第 0 位为不可执行，第 1、2 位为 Svpbmt 内存类型
+///
+/// `vm_attributes_t` on riscv: bit 0 is execute-never, bits[2:1] the Svpbmt memory type.
+impl vm_attributes_t {
+    pub const EXECUTE_NEVER: usize = bit!(0);
+    pub const MEMORY_TYPE_SHIFT: usize = 1;
+    pub const MEMORY_TYPE: usize = 0x3 << Self::MEMORY_TYPE_SHIFT;
+    pub const VALID_BITS: usize = Self::EXECUTE_NEVER | Self::MEMORY_TYPE;
+
+    pub const fn new_riscv(execute_never: bool, memory_type: pbmt_t) -> Self {
+        Self(
+            (execute_never as usize) * Self::EXECUTE_NEVER
+                | (memory_type as usize) << Self::MEMORY_TYPE_SHIFT,
+        )
+    }
+
+    pub fn get_riscv_execute_never(&self) -> bool {
+        self.0 & Self::EXECUTE_NEVER != 0
+    }
+
+    /// 保留的类型值 3 按最严格的`IO`处理
+    ///
+    /// The reserved value 3 is treated as the most restrictive, I/O.
+    pub fn get_riscv_memory_type(&self) -> pbmt_t {
+        match (self.0 & Self::MEMORY_TYPE) >> Self::MEMORY_TYPE_SHIFT {
+            0 => pbmt_t::PMA,
+            1 => pbmt_t::NC,
+            _ => pbmt_t::IO,
+        }
+    }
+
+    pub(crate) fn is_page_cacheable(&self) -> bool {
+        self.get_riscv_memory_type() == pbmt_t::PMA
+    }
+}
 
 ///lookup_pt_slot函数的返回值，
 /// `ptSlot`：找到的虚地址对应的`pte`的存放槽
@@ -1,6 +1,81 @@
 use rel4_arch::basic::PAddr;
 use sel4_common::arch::config::KERNEL_ELF_BASE_OFFSET;
-use sel4_common::sel4_config::{CONFIG_PT_LEVELS, PT_INDEX_BITS, SEL4_PAGE_BITS};
+use sel4_common::sel4_config::{
+    CONFIG_PT_LEVELS, PT_INDEX_BITS, SEL4_PAGE_BITS, SEL4_PAGE_TABLE_BITS,
+};
+
+/// 虚拟地址的位数，Sv39 为 39，Sv48 为 48
+///
+/// Bits of a virtual address, 39 with Sv39 and 48 with Sv48.
+pub const VA_BITS: usize = PT_INDEX_BITS * CONFIG_PT_LEVELS + SEL4_PAGE_BITS;
+/// 用户地址空间根页表的大小位数，根页表对象需要按此分配与对齐
+///
+/// Size bits of the root table of a user vspace, its object is allocated and aligned to it.
+#[cfg(not(feature = "hypervisor"))]
+pub const VSPACE_ROOT_BITS: usize = SEL4_PAGE_TABLE_BITS;
+/// 用户地址空间根页表的大小位数，G-stage 根页表为 16 KiB
+///
+/// Size bits of the root table of a user vspace, 16 KiB for a G-stage root.
+#[cfg(feature = "hypervisor")]
+pub const VSPACE_ROOT_BITS: usize = super::GSTAGE_ROOT_TABLE_BITS;
+/// 用户地址空间的上界（不含），即规范地址的低半部分
+///
+/// End of the user range, the lower canonical half.
+#[cfg(not(feature = "hypervisor"))]
+pub const USER_TOP: usize = bit!(VA_BITS - 1);
+/// 用户地址空间的上界（不含），即客户物理地址的范围
+///
+/// End of the user range: the guest physical range of the G-stage vspaces.
+#[cfg(feature = "hypervisor")]
+pub const USER_TOP: usize = bit!(super::GPA_BITS);
+/// 规范地址高半部分的起始地址，属于内核
+///
+/// Start of the upper canonical half, which belongs to the kernel.
+#[cfg(not(feature = "hypervisor"))]
+pub const KERNEL_HALF_BASE: Option<usize> = Some(!mask_bits!(VA_BITS - 1));
+/// 客户物理地址没有内核部分，`USER_TOP`以上均越界
+///
+/// Guest physical addresses have no kernel half, anything above `USER_TOP` is out of range.
+#[cfg(feature = "hypervisor")]
+pub const KERNEL_HALF_BASE: Option<usize> = None;
+/// 每种`PageSize`对应的位数，按`frame cap`的`size`字段索引
+///
+/// Bits of each `PageSize`, indexed by the size field of the frame cap.
+#[cfg(not(feature = "riscv_svnapot"))]
+pub(crate) const PAGE_SIZE_BITS: [usize; 3] = [
+    SEL4_PAGE_BITS,
+    SEL4_PAGE_BITS + PT_INDEX_BITS,
+    SEL4_PAGE_BITS + 2 * PT_INDEX_BITS,
+];
+/// 每种`PageSize`对应的位数，NAPOT 页在最后
+///
+/// Bits of each `PageSize`, the NAPOT page comes last.
+#[cfg(feature = "riscv_svnapot")]
+pub(crate) const PAGE_SIZE_BITS: [usize; 4] = [
+    SEL4_PAGE_BITS,
+    SEL4_PAGE_BITS + PT_INDEX_BITS,
+    SEL4_PAGE_BITS + 2 * PT_INDEX_BITS,
+    super::NAPOT_64K_BITS,
+];
+/// 每种`PageSize`的叶子页表项所在的页表级数
+///
+/// Level of the leaf entry of each `PageSize` in the page table walk.
+#[cfg(not(feature = "riscv_svnapot"))]
+pub(crate) const PAGE_SIZE_LEVELS: [usize; 3] = [
+    CONFIG_PT_LEVELS - 1,
+    CONFIG_PT_LEVELS - 2,
+    CONFIG_PT_LEVELS - 3,
+];
+/// 每种`PageSize`的叶子页表项所在的页表级数，NAPOT 组位于最后一级
+///
+/// Level of the leaf entry of each `PageSize`, a NAPOT group is made of last level entries.
+#[cfg(feature = "riscv_svnapot")]
+pub(crate) const PAGE_SIZE_LEVELS: [usize; 4] = [
+    CONFIG_PT_LEVELS - 1,
+    CONFIG_PT_LEVELS - 2,
+    CONFIG_PT_LEVELS - 3,
+    CONFIG_PT_LEVELS - 1,
+];
 
 ///获得虚拟地址`addr`对应的`n`级VPN，
 /// 具体对应关系为:
@@ -15,6 +90,17 @@ pub fn riscv_get_pt_index(addr: usize, n: usize) -> usize {
         & mask_bits!(PT_INDEX_BITS)
 }
 
+///获得用户地址空间中`addr`对应的`n`级页表索引，`hypervisor`下根页表的索引更宽
+///
+/// Get the level `n` index of `addr` in a user vspace, whose root is wider under `hypervisor`.
+#[inline]
+pub fn vspace_get_pt_index(addr: usize, n: usize) -> usize {
+    #[cfg(feature = "hypervisor")]
+    return super::gstage_get_pt_index(addr, n);
+    #[cfg(not(feature = "hypervisor"))]
+    riscv_get_pt_index(addr, n)
+}
+
 /// 获得第n级页表对应的虚拟地址空间的大小位数
 /// 根页表对应2^30=1GB,30位
 /// 一级页表对应2^21=2MB，21位
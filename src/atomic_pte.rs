@@ -0,0 +1,57 @@
+//! Atomic access to page table entries.
+//!
+//! With hardware access and dirty bit updates (Svadu, FEAT_HAFDBS) the table walker may set
+//! bits of an entry at any time, so read-modify-write updates of live entries go through
+//! compare-and-swap to never lose a bit set by the hardware. Like `update`, they first split
+//! the hardware group (contiguous hint, NAPOT) the entry belongs to.
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::PTE;
+
+impl PTE {
+    /// The entry as an atomic word.
+    #[inline]
+    pub fn atomic(&self) -> &AtomicUsize {
+        unsafe { &*(self as *const Self as *const AtomicUsize) }
+    }
+
+    #[inline]
+    pub fn load(&self) -> Self {
+        PTE(self.atomic().load(Ordering::Acquire))
+    }
+
+    /// Replace the entry with `new` if it still is `current`, returns the entry found.
+    #[inline]
+    pub fn compare_exchange(&self, current: Self, new: Self) -> Result<Self, Self> {
+        self.prepare_update();
+        let ret = self
+            .atomic()
+            .compare_exchange(current.0, new.0, Ordering::AcqRel, Ordering::Acquire)
+            .map(PTE)
+            .map_err(PTE);
+        if ret.is_ok() {
+            self.sync_update();
+        }
+        ret
+    }
+
+    /// Replace the entry with `f(entry)`, retrying if the entry changed in between.
+    ///
+    /// Returns `Ok` with the entry that was replaced, or `Err` with the entry `f` returned
+    /// `None` for.
+    #[inline]
+    pub fn fetch_update(&self, mut f: impl FnMut(Self) -> Option<Self>) -> Result<Self, Self> {
+        self.prepare_update();
+        let ret = self
+            .atomic()
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |pte| {
+                f(PTE(pte)).map(|pte| pte.0)
+            })
+            .map(PTE)
+            .map_err(PTE);
+        if ret.is_ok() {
+            self.sync_update();
+        }
+        ret
+    }
+}
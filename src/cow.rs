@@ -0,0 +1,174 @@
+//! 帧映射的写时复制
+//!
+//! Copy-on-write of frame mappings.
+//!
+//! Sharing a range write-protects its writable leaves and tags them with a software bit. A write
+//! fault on a tagged leaf is recognised by `cow_fault_lookup`, the caller copies the frame and
+//! `cow_fault_resolve` maps the copy writable in the faulting vspace only.
+use rel4_arch::basic::{PAddr, VPtr};
+
+use crate::arch::{invalidate_tlb_by_asid, invalidate_tlb_by_asid_va, lookup_vspace_slot};
+use crate::utils::{for_each_leaf, for_each_leaf_mut};
+use crate::vaddr::{check_user_range, check_user_vaddr, vaddr_error_t};
+use crate::{asid_t, PTE};
+
+/// 写时复制错误：叶子的起始虚拟地址、出错时的页表项、共享帧及其大小
+///
+/// A copy-on-write fault: the virtual address of the faulting leaf, the leaf itself, and the
+/// shared frame to copy from. `cow_fault_resolve` walks the vspace again, the tables may have
+/// changed in between.
+#[derive(Debug)]
+pub struct cow_fault_t {
+    pte: PTE,
+    pub vaddr: VPtr,
+    pub frame: PAddr,
+    pub size_bits: usize,
+}
+
+/// 写时复制操作失败的原因
+///
+/// Why a copy-on-write operation failed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum cow_error_t {
+    /// 地址范围不合法
+    ///
+    /// The range is not a valid user range.
+    Vaddr(vaddr_error_t),
+    /// 两个地址空间在该范围内映射的帧不同
+    ///
+    /// The two vspaces do not map the same frames in the range.
+    NotShared,
+    /// 新的帧没有按叶子的大小对齐
+    ///
+    /// The new frame is not aligned to the size of the leaf.
+    Misaligned,
+    /// 叶子在查找之后被修改，应重试该错误
+    ///
+    /// The leaf changed since the lookup, the fault should be retried.
+    Changed,
+}
+
+impl From<vaddr_error_t> for cow_error_t {
+    fn from(err: vaddr_error_t) -> Self {
+        Self::Vaddr(err)
+    }
+}
+
+/// 将一个地址空间中`[start, end)`的可写叶子页表项标记为只读写时复制
+///
+/// Make the writable leaves of `[start, end)` read-only copy-on-write in one vspace, the range
+/// has to be page aligned and in the user range.
+pub fn cow_protect_range(
+    vspace_root: *mut PTE,
+    asid: asid_t,
+    start: VPtr,
+    end: VPtr,
+) -> Result<(), vaddr_error_t> {
+    check_user_range(start, end)?;
+    if for_each_leaf_mut(vspace_root, asid, start.raw(), end.raw(), |pte, _, _| {
+        pte.fetch_update(|pte| pte.cow_protected()).is_ok()
+    }) {
+        invalidate_tlb_by_asid(asid);
+    }
+    Ok(())
+}
+
+/// `root_b`是否在`root_a`的每个叶子处映射同一个帧，且大小相同
+///
+/// Whether `root_b` maps the frame of every leaf of `root_a` in `[start, end)`, with a leaf of
+/// the same size, and nothing where `root_a` maps nothing.
+fn maps_same_frames(root_a: *mut PTE, root_b: *mut PTE, start: usize, end: usize) -> bool {
+    let mut same = true;
+    for_each_leaf(root_a, start, end, |slot, leaf_start, leaf_end| {
+        let pte = slot.load();
+        let lu_ret = lookup_vspace_slot(root_b, vptr!(leaf_start));
+        let other = unsafe { &*lu_ret.ptSlot }.load();
+        same &= if pte.is_leaf() {
+            other.is_leaf()
+                && bit!(lu_ret.ptBitsLeft) == leaf_end - leaf_start
+                && other.get_page_base_address() == pte.get_page_base_address()
+        } else {
+            !other.is_leaf()
+        };
+        false
+    });
+    same
+}
+
+/// 在共享`[start, end)`的两个地址空间中将其标记为写时复制，两者需映射相同的帧
+///
+/// Mark `[start, end)` copy-on-write in the two vspaces sharing it, which have to map the same
+/// frames there.
+pub fn cow_share_range(
+    (root_a, asid_a): (*mut PTE, asid_t),
+    (root_b, asid_b): (*mut PTE, asid_t),
+    start: VPtr,
+    end: VPtr,
+) -> Result<(), cow_error_t> {
+    check_user_range(start, end)?;
+    if !maps_same_frames(root_a, root_b, start.raw(), end.raw())
+        || !maps_same_frames(root_b, root_a, start.raw(), end.raw())
+    {
+        return Err(cow_error_t::NotShared);
+    }
+    cow_protect_range(root_a, asid_a, start, end)?;
+    cow_protect_range(root_b, asid_b, start, end)?;
+    Ok(())
+}
+
+/// 判断对`vaddr`的写错误是否由写时复制引起
+///
+/// Recognise a write fault on `vaddr` of the vspace of `asid` caused by copy-on-write.
+pub fn cow_fault_lookup(vspace_root: *mut PTE, asid: asid_t, vaddr: VPtr) -> Option<cow_fault_t> {
+    check_user_vaddr(vaddr).ok()?;
+    let lu_ret = lookup_vspace_slot(vspace_root, vaddr);
+    let slot = unsafe { &*lu_ret.ptSlot };
+    if !slot.load().is_cow() {
+        return None;
+    }
+    // Split a contiguous / NAPOT group first, so that the entry expected by
+    // `cow_fault_resolve` is not changed by the split done before its exchange.
+    slot.split_group(asid, vaddr, lu_ret.ptBitsLeft);
+    let pte = slot.load();
+    if !pte.is_cow() {
+        return None;
+    }
+    let offset_mask = mask_bits!(lu_ret.ptBitsLeft);
+    Some(cow_fault_t {
+        pte,
+        vaddr: vptr!(vaddr.raw() & !offset_mask),
+        frame: pte.get_page_base_address(),
+        size_bits: lu_ret.ptBitsLeft,
+    })
+}
+
+/// 将调用者复制好的`new_frame`可写地映射到出错的地址空间，只清除该地址的`TLB`
+///
+/// Map `new_frame`, already holding a copy of `fault.frame`, writable in the faulting vspace
+/// and invalidate that address only. The leaf is looked up again, `cow_error_t::Changed` means
+/// it changed since `cow_fault_lookup` and the fault should be retried.
+pub fn cow_fault_resolve(
+    vspace_root: *mut PTE,
+    asid: asid_t,
+    fault: cow_fault_t,
+    new_frame: PAddr,
+) -> Result<(), cow_error_t> {
+    if new_frame.raw() & mask_bits!(fault.size_bits) != 0 {
+        return Err(cow_error_t::Misaligned);
+    }
+    let lu_ret = lookup_vspace_slot(vspace_root, fault.vaddr);
+    if lu_ret.ptBitsLeft != fault.size_bits {
+        return Err(cow_error_t::Changed);
+    }
+    let slot = unsafe { &*lu_ret.ptSlot };
+    if slot
+        .compare_exchange(fault.pte, fault.pte.cow_broken(new_frame))
+        .is_err()
+    {
+        return Err(cow_error_t::Changed);
+    }
+    invalidate_tlb_by_asid_va(asid, fault.vaddr);
+    #[cfg(feature = "reverse_map")]
+    crate::rmap_move(fault.frame, new_frame, asid, fault.vaddr);
+    Ok(())
+}
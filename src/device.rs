@@ -0,0 +1,126 @@
+//! Kernel device regions with their memory type and size.
+
+use rel4_arch::basic::{PAddr, PPtr};
+use rel4_utils::no_lock::NoLock;
+use sel4_common::sel4_config::PAGE_BITS;
+
+/// 内核设备映射的内存类型，按限制从强到弱排列
+///
+/// Memory type of a kernel device mapping, from the most to the least restrictive.
+/// On riscv the device types map to Svpbmt `IO` and `NORMAL_NC` to `NC`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum device_memory_t {
+    /// Device, non-Gathering, non-Reordering, no Early write acknowledgement.
+    #[default]
+    DEVICE_nGnRnE,
+    /// Device, non-Gathering, non-Reordering, Early write acknowledgement.
+    DEVICE_nGnRE,
+    /// Device, Gathering, Reordering, Early write acknowledgement.
+    DEVICE_GRE,
+    /// Normal memory, inner/outer non-cacheable.
+    NORMAL_NC,
+}
+
+/// 内核设备区域不合法的原因
+///
+/// Why a kernel device region was rejected.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum kernel_device_error_t {
+    /// 区域小于一页
+    ///
+    /// The region is smaller than a page.
+    TooSmall,
+    /// 物理地址与虚拟地址没有按映射它们的叶子对齐
+    ///
+    /// The physical and virtual addresses are not aligned alike for the leaves mapping them.
+    Misaligned,
+}
+
+/// 平台为`platform::kernel_device_frames`中的设备补充的内存类型和大小，按物理地址对应
+///
+/// Memory type and size of a device of `platform::kernel_device_frames`, matched by its
+/// physical address. The entries of that table are single `DEVICE_nGnRnE` pages, a platform
+/// describes its other devices here.
+#[derive(Debug, Clone, Copy)]
+pub struct kernel_device_attr_t {
+    pub paddr: PAddr,
+    pub memory_type: device_memory_t,
+    pub size_bits: usize,
+}
+
+static KERNEL_DEVICE_ATTRS: NoLock<&'static [kernel_device_attr_t]> = NoLock::new(&[]);
+
+/// 设置内核设备的内存类型和大小，需在映射内核窗口之前调用
+///
+/// Set the memory types and sizes of the kernel devices, before the kernel window is mapped.
+pub fn set_kernel_device_attrs(attrs: &'static [kernel_device_attr_t]) {
+    *KERNEL_DEVICE_ATTRS.no_lock() = attrs;
+}
+
+/// 内核设备区域，比`platform::kernel_device_frames`多记录内存类型和大小
+///
+/// A kernel device region. Unlike the entries of `platform::kernel_device_frames` it
+/// carries a memory type and may span several pages, `size_bits` being the region size.
+#[derive(Debug, Clone, Copy)]
+pub struct kernel_device_frame_t {
+    pub paddr: PAddr,
+    pub pptr: PPtr,
+    pub size_bits: usize,
+    pub memory_type: device_memory_t,
+    pub execute_never: bool,
+    pub user_available: bool,
+}
+
+impl kernel_device_frame_t {
+    /// A single page of `DEVICE_nGnRnE` memory, what `kernel_device_frames` describes.
+    pub fn new(paddr: PAddr, pptr: PPtr, execute_never: bool, user_available: bool) -> Self {
+        Self {
+            paddr,
+            pptr,
+            size_bits: PAGE_BITS,
+            memory_type: device_memory_t::DEVICE_nGnRnE,
+            execute_never,
+            user_available,
+        }
+    }
+
+    pub fn with_memory_type(mut self, memory_type: device_memory_t) -> Self {
+        self.memory_type = memory_type;
+        self
+    }
+
+    pub fn with_size_bits(mut self, size_bits: usize) -> Result<Self, kernel_device_error_t> {
+        if size_bits < PAGE_BITS {
+            return Err(kernel_device_error_t::TooSmall);
+        }
+        self.size_bits = size_bits;
+        Ok(self)
+    }
+
+    /// 平台设备表中的一项，应用`set_kernel_device_attrs`给出的内存类型和大小
+    ///
+    /// An entry of `platform::kernel_device_frames`, with the memory type and size given to
+    /// `set_kernel_device_attrs` for its address.
+    pub fn from_platform(
+        paddr: PAddr,
+        pptr: PPtr,
+        execute_never: bool,
+        user_available: bool,
+    ) -> Result<Self, kernel_device_error_t> {
+        let frame = Self::new(paddr, pptr, execute_never, user_available);
+        match KERNEL_DEVICE_ATTRS
+            .no_lock()
+            .iter()
+            .find(|attr| attr.paddr == paddr)
+        {
+            Some(attr) => frame
+                .with_memory_type(attr.memory_type)
+                .with_size_bits(attr.size_bits),
+            None => Ok(frame),
+        }
+    }
+
+    pub fn size(&self) -> usize {
+        bit!(self.size_bits)
+    }
+}
@@ -0,0 +1,150 @@
+//! 客户机内存的脏页记录，用于热迁移与快照
+//!
+//! Dirty page logging of guest memory, for live migration and checkpointing.
+//!
+//! While logging, the writable stage 2 leaves of a guest physical range are mapped read-only.
+//! The first write to each of them faults, is recorded in the bitmap of the range and the write
+//! access is given back. Fetching the bitmap write-protects the range again before resetting
+//! it, so that the next round only sees new writes.
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use rel4_arch::basic::VPtr;
+use sel4_common::sel4_config::SEL4_PAGE_BITS;
+
+use crate::arch::{invalidate_tlb_by_asid, invalidate_tlb_by_asid_va, lookup_vspace_slot};
+use crate::utils::for_each_leaf_mut;
+use crate::vaddr::{check_user_range, vaddr_error_t};
+use crate::{asid_t, PTE};
+
+const BITS_PER_WORD: usize = usize::BITS as usize;
+
+/// 一段客户物理地址的脏页位图，每一位对应一个 4 KiB 页
+///
+/// The dirty bitmap of a guest physical range, one bit per 4 KiB page.
+pub struct dirty_log_t {
+    base: usize,
+    bitmap: &'static [AtomicUsize],
+}
+
+impl dirty_log_t {
+    /// 记录从`base`开始、`bitmap`所能覆盖的页，该范围需页对齐且位于客户物理地址范围内
+    ///
+    /// Log the pages from `base` covered by `bitmap`, which have to be page aligned and in the
+    /// guest physical range.
+    pub fn new(base: VPtr, bitmap: &'static [AtomicUsize]) -> Result<Self, vaddr_error_t> {
+        let end = ((bitmap.len() * BITS_PER_WORD) << SEL4_PAGE_BITS)
+            .checked_add(base.raw())
+            .ok_or(vaddr_error_t::InvalidRange)?;
+        check_user_range(base, vptr!(end))?;
+        Ok(Self {
+            base: base.raw(),
+            bitmap,
+        })
+    }
+
+    #[inline]
+    fn end(&self) -> usize {
+        self.base + ((self.bitmap.len() * BITS_PER_WORD) << SEL4_PAGE_BITS)
+    }
+
+    #[inline]
+    pub fn contains(&self, ipa: usize) -> bool {
+        ipa >= self.base && ipa < self.end()
+    }
+
+    fn mark(&self, start: usize, end: usize) {
+        let first = (start.max(self.base) - self.base) >> SEL4_PAGE_BITS;
+        let last = (end.min(self.end()) - self.base) >> SEL4_PAGE_BITS;
+        for page in first..last {
+            self.bitmap[page / BITS_PER_WORD]
+                .fetch_or(bit!(page % BITS_PER_WORD), Ordering::Relaxed);
+        }
+    }
+
+    /// 原子地取出并清空位图，`out`需与位图等长
+    ///
+    /// Atomically move the bitmap into `out`, which must be as long as the bitmap.
+    fn fetch_and_reset(&self, out: &mut [usize]) {
+        assert_eq!(out.len(), self.bitmap.len());
+        for (word, dirty) in out.iter_mut().zip(self.bitmap) {
+            *word = dirty.swap(0, Ordering::AcqRel);
+        }
+    }
+}
+
+/// 开始记录：写保护范围内所有可写的叶子页表项
+///
+/// Start logging: write-protect every writable leaf of the range.
+pub fn dirty_log_start(vspace_root: *mut PTE, asid: asid_t, log: &dirty_log_t) {
+    if for_each_leaf_mut(vspace_root, asid, log.base, log.end(), |pte, _, _| {
+        pte.fetch_update(|pte| pte.dirty_log_protected()).is_ok()
+    }) {
+        invalidate_tlb_by_asid(asid);
+    }
+}
+
+/// 停止记录：恢复范围内所有被写保护的叶子页表项
+///
+/// Stop logging: give back the write access of every leaf still protected.
+pub fn dirty_log_stop(vspace_root: *mut PTE, asid: asid_t, log: &dirty_log_t) {
+    if for_each_leaf_mut(vspace_root, asid, log.base, log.end(), |pte, _, _| {
+        pte.fetch_update(|pte| pte.dirty_log_unprotected()).is_ok()
+    }) {
+        invalidate_tlb_by_asid(asid);
+    }
+}
+
+/// 处理客户机对`ipa`的写错误，若由脏页记录引起则记录该页并恢复写权限
+///
+/// Handle a guest write fault on `ipa`. Returns `true` if it was caused by dirty logging, in
+/// which case the page is recorded, writable again, and the guest can be resumed.
+pub fn dirty_log_handle_write_fault(
+    vspace_root: *mut PTE,
+    asid: asid_t,
+    log: &dirty_log_t,
+    ipa: usize,
+) -> bool {
+    if !log.contains(ipa) {
+        return false;
+    }
+    let lu_ret = lookup_vspace_slot(vspace_root, vptr!(ipa));
+    let slot = unsafe { &*lu_ret.ptSlot };
+    slot.split_group(asid, vptr!(ipa), lu_ret.ptBitsLeft);
+    if slot
+        .fetch_update(|pte| pte.dirty_log_unprotected())
+        .is_err()
+    {
+        return false;
+    }
+    let leaf_start = ipa & !mask_bits!(lu_ret.ptBitsLeft);
+    log.mark(leaf_start, leaf_start + bit!(lu_ret.ptBitsLeft));
+    invalidate_tlb_by_asid_va(asid, vptr!(leaf_start));
+    true
+}
+
+/// 重新写保护范围内所有可写的页并刷新`TLB`后，原子地取出并清空位图，使下一轮只记录新的写
+///
+/// Write-protect the range again and flush the TLB, then atomically move the bitmap into
+/// `out`, so that the next round only sees new writes.
+///
+/// Once the TLB is flushed every write faults, so none can slip between the bitmap swap and the
+/// protection. A page which faulted before the swap is in `out` but writable again, it is
+/// protected once more after the swap. A write to it in between needs no record, the page is
+/// reported dirty and copied after this returns.
+pub fn dirty_log_fetch_and_reset(
+    vspace_root: *mut PTE,
+    asid: asid_t,
+    log: &dirty_log_t,
+    out: &mut [usize],
+) {
+    dirty_log_start(vspace_root, asid, log);
+    log.fetch_and_reset(out);
+    let dirty = |page: usize| out[page / BITS_PER_WORD] & bit!(page % BITS_PER_WORD) != 0;
+    if for_each_leaf_mut(vspace_root, asid, log.base, log.end(), |pte, start, end| {
+        let first = (start.max(log.base) - log.base) >> SEL4_PAGE_BITS;
+        let last = (end.min(log.end()) - log.base) >> SEL4_PAGE_BITS;
+        (first..last).any(&dirty) && pte.fetch_update(|pte| pte.dirty_log_protected()).is_ok()
+    }) {
+        invalidate_tlb_by_asid(asid);
+    }
+}
@@ -0,0 +1,168 @@
+//! Layout of the kernel image, taken from the symbols exported by the linker script.
+
+use rel4_arch::basic::PRegion;
+use sel4_common::sel4_config::SEL4_PAGE_BITS;
+
+extern "C" {
+    /// Start of `.boot.text`, the code only used while booting.
+    static ki_boot_start: u8;
+    /// End of `.boot.text`.
+    static ki_boot_end: u8;
+    /// Start of `.text`, also the start of the kernel image.
+    static ki_text_start: u8;
+    /// End of `.text`, start of `.rodata`.
+    static ki_text_end: u8;
+    /// End of `.rodata`, start of `.data` and `.bss`.
+    static ki_rodata_end: u8;
+    /// End of the kernel image.
+    static ki_end: u8;
+}
+
+/// The number of section boundaries in the kernel image.
+///
+/// Each boundary splits at most one large mapping of the kernel window into small pages,
+/// so this is also the number of page tables the kernel window needs for the image.
+pub(crate) const KERNEL_IMAGE_BOUNDARIES: usize = 6;
+
+/// 内核镜像中的段，决定内核窗口中对应页的权限
+///
+/// Sections of the kernel image, mapped with different permissions:
+/// `BootText` and `Text` are RX, `RoData` is R and `Data` (`.data`, `.bss` and everything
+/// outside the image) is RW-NX. The pages of `BootText` become `Data` once booting is done.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KernelSection {
+    BootText,
+    Text,
+    RoData,
+    Data,
+}
+
+/// Virtual addresses of the section boundaries of the kernel image.
+#[derive(Debug, Clone, Copy)]
+pub struct KernelImageLayout {
+    pub boot_start: usize,
+    pub boot_end: usize,
+    pub text_start: usize,
+    pub text_end: usize,
+    pub rodata_end: usize,
+    pub end: usize,
+}
+
+impl KernelImageLayout {
+    /// Read the layout of the running kernel from the linker symbols.
+    pub fn current() -> Self {
+        unsafe {
+            Self {
+                boot_start: &raw const ki_boot_start as usize,
+                boot_end: &raw const ki_boot_end as usize,
+                text_start: &raw const ki_text_start as usize,
+                text_end: &raw const ki_text_end as usize,
+                rodata_end: &raw const ki_rodata_end as usize,
+                end: &raw const ki_end as usize,
+            }
+        }
+    }
+
+    #[inline]
+    fn boundaries(&self) -> [usize; KERNEL_IMAGE_BOUNDARIES] {
+        [
+            self.boot_start,
+            self.boot_end,
+            self.text_start,
+            self.text_end,
+            self.rodata_end,
+            self.end,
+        ]
+    }
+
+    /// Get the section of a kernel virtual address,
+    /// addresses outside the kernel image are treated as data.
+    pub fn section_of(&self, vaddr: usize) -> KernelSection {
+        if vaddr >= self.boot_start && vaddr < self.boot_end {
+            KernelSection::BootText
+        } else if vaddr >= self.text_start && vaddr < self.text_end {
+            KernelSection::Text
+        } else if vaddr >= self.text_end && vaddr < self.rodata_end {
+            KernelSection::RoData
+        } else {
+            KernelSection::Data
+        }
+    }
+
+    /// Get the section of `[start, end)` if the whole range lies in one section,
+    /// otherwise the range has to be mapped with smaller pages.
+    pub fn uniform_section(&self, start: usize, end: usize) -> Option<KernelSection> {
+        if self
+            .boundaries()
+            .iter()
+            .any(|&boundary| start < boundary && boundary < end)
+        {
+            return None;
+        }
+        Some(self.section_of(start))
+    }
+
+    /// Get the section of a kernel virtual address once `.boot.text` is reclaimed,
+    /// the pages fully covered by `.boot.text` are data from then on.
+    pub fn reclaimed_section_of(&self, vaddr: usize) -> KernelSection {
+        let (start, end) = self.boot_text_pages();
+        if vaddr >= start && vaddr < end {
+            KernelSection::Data
+        } else {
+            self.section_of(vaddr)
+        }
+    }
+
+    /// Whether every page of `[start, end)` is data once `.boot.text` is reclaimed,
+    /// in which case the range can be mapped by a single large page again.
+    pub fn reclaimed_all_data(&self, start: usize, end: usize) -> bool {
+        (start..end)
+            .step_by(bit!(SEL4_PAGE_BITS))
+            .all(|vaddr| self.reclaimed_section_of(vaddr) == KernelSection::Data)
+    }
+
+    /// The pages fully covered by `.boot.text`, as `[start, end)` kernel virtual addresses.
+    pub fn boot_text_pages(&self) -> (usize, usize) {
+        let page_mask = mask_bits!(SEL4_PAGE_BITS);
+        let start = (self.boot_start + page_mask) & !page_mask;
+        let end = self.boot_end & !page_mask;
+        (start, end.max(start))
+    }
+}
+
+/// 回收`.boot.text`后释放的物理内存：`.boot.text`本身以及因此不再使用的内核镜像页表
+///
+/// The physical regions freed by `reclaim_boot_text`: `.boot.text` itself and the page tables
+/// of the kernel image it leaves unused, all of them can be handed to the untyped allocator.
+pub struct ReclaimedRegions {
+    regions: [Option<PRegion>; KERNEL_IMAGE_BOUNDARIES + 1],
+    len: usize,
+}
+
+impl ReclaimedRegions {
+    pub(crate) const fn new() -> Self {
+        Self {
+            regions: [const { None }; KERNEL_IMAGE_BOUNDARIES + 1],
+            len: 0,
+        }
+    }
+
+    pub(crate) fn push(&mut self, region: PRegion) {
+        self.regions[self.len] = Some(region);
+        self.len += 1;
+    }
+
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &PRegion> {
+        self.regions[..self.len].iter().flatten()
+    }
+}
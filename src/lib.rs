@@ -11,13 +11,31 @@ extern crate rel4_utils;
 #[macro_use]
 extern crate rel4_arch;
 
+#[cfg(feature = "access_tracking")]
+mod access;
 pub mod arch;
 mod asid;
+mod atomic_pte;
 mod boot;
+#[cfg(feature = "cow")]
+mod cow;
+mod device;
+#[cfg(feature = "hypervisor")]
+mod dirty_log;
+mod kernel_image;
+#[cfg(feature = "hypervisor")]
+mod nested;
+mod page_size;
 // mod pte;
+#[cfg(feature = "reverse_map")]
+mod rmap;
 mod structures;
+mod sw_bits;
 mod utils;
+mod vaddr;
 
+#[cfg(feature = "access_tracking")]
+pub use access::{handle_access_fault, harvest_access_bits, hw_access_dirty};
 #[cfg(target_arch = "aarch64")]
 pub use arch::aarch64::*;
 #[cfg(target_arch = "riscv64")]
@@ -25,7 +43,34 @@ pub use arch::riscv64::*;
 pub use arch::unmap_page;
 pub use asid::*;
 pub use boot::*;
+#[cfg(feature = "cow")]
+pub use cow::{
+    cow_error_t, cow_fault_lookup, cow_fault_resolve, cow_fault_t, cow_protect_range,
+    cow_share_range,
+};
+pub use device::{
+    device_memory_t, kernel_device_attr_t, kernel_device_error_t, kernel_device_frame_t,
+    set_kernel_device_attrs,
+};
+#[cfg(feature = "hypervisor")]
+pub use dirty_log::{
+    dirty_log_fetch_and_reset, dirty_log_handle_write_fault, dirty_log_start, dirty_log_stop,
+    dirty_log_t,
+};
+pub use kernel_image::{KernelImageLayout, KernelSection, ReclaimedRegions};
+#[cfg(feature = "hypervisor")]
+pub use nested::{
+    nested_fault_t, translate_gva, translate_ipa, translation_stage_t, walk_geometry_t,
+};
+pub use page_size::PageSize;
 // pub use pte::PTE;
+#[cfg(feature = "reverse_map")]
+pub use rmap::{
+    rmap_entry_t, rmap_error_t, rmap_for_each, rmap_insert, rmap_move, rmap_remove,
+    rmap_remove_asids, rmap_unmap_all, RMAP_ENTRIES,
+};
 pub use structures::*;
+pub use sw_bits::pte_sw_bit_t;
 pub use utils::check_vp_alignment;
+pub use vaddr::{check_user_frame, check_user_range, check_user_vaddr, vaddr_error_t};
 // pub use riscv::*;
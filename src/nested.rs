@@ -0,0 +1,183 @@
+//! 二阶段嵌套地址翻译，用于指令模拟和`MMIO`模拟时读取客户机内存
+//!
+//! Software walk of a guest virtual address through the guest's stage 1 tables and then the
+//! stage 2 tables, as done by the hardware, for fetching faulting instructions or emulating MMIO.
+use rel4_arch::basic::{PAddr, VPtr};
+
+use crate::arch::S2_GEOMETRY;
+use crate::PTE;
+
+/// 发生错误的翻译阶段
+///
+/// The translation stage that faulted.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum translation_stage_t {
+    /// 客户机自己的页表（客户虚拟地址到客户物理地址）
+    ///
+    /// The guest's own tables, guest virtual to guest physical.
+    Stage1,
+    /// 虚拟机监控器的页表（客户物理地址到物理地址）
+    ///
+    /// The hypervisor's tables, guest physical to physical.
+    Stage2,
+}
+
+/// 嵌套翻译的错误：阶段、级数以及该阶段正在翻译的地址
+///
+/// A nested translation fault. `addr` is the input address of the faulting stage: the guest
+/// virtual address for stage 1, the guest physical address for stage 2, which may be the
+/// address of a stage 1 table.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct nested_fault_t {
+    pub stage: translation_stage_t,
+    pub level: usize,
+    pub addr: usize,
+}
+
+/// 一个阶段页表的几何参数，客户机第一阶段的由客户机的`TCR_EL1`或`vsatp`得到
+///
+/// Geometry of the tables of one stage. The guest's stage 1 one is derived from the guest's
+/// `TCR_EL1` or `vsatp` by `walk_geometry_t::from_tcr_el1` / `walk_geometry_t::from_vsatp`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct walk_geometry_t {
+    /// 遍历的级数，0 表示客户机关闭了翻译
+    ///
+    /// Levels of the walk, 0 when the guest runs with translation off.
+    pub levels: usize,
+    /// 页大小的位数
+    ///
+    /// Size bits of a page.
+    pub granule_bits: usize,
+    /// 非根页表的索引位数
+    ///
+    /// Index bits of every table but the root.
+    pub index_bits: usize,
+    /// 根页表的索引位数，由输入地址范围决定
+    ///
+    /// Index bits of the root, what is left of the input range.
+    pub root_index_bits: usize,
+    /// 输入地址需要符号扩展（riscv），否则高位需要为 0（aarch64 的 TTBR0）
+    ///
+    /// Input addresses are sign-extended (riscv), otherwise their upper bits are 0 (aarch64
+    /// TTBR0 range).
+    pub sign_extend: bool,
+    /// 输出地址的位数，决定 aarch64 叶子中地址字段的编码
+    ///
+    /// Bits of the output addresses, which decides how aarch64 descriptors encode them: 52 is
+    /// the FEAT_LPA / FEAT_LPA2 encoding.
+    pub output_bits: usize,
+}
+
+impl walk_geometry_t {
+    /// 第`level`级页表项映射的大小（位数）
+    ///
+    /// Size bits of the region mapped by an entry of `level`.
+    #[inline]
+    pub const fn level_bits(&self, level: usize) -> usize {
+        self.granule_bits + self.index_bits * (self.levels - 1 - level)
+    }
+
+    /// 输入地址的位数
+    ///
+    /// Bits of an input address.
+    #[inline]
+    pub const fn input_bits(&self) -> usize {
+        self.level_bits(0) + self.root_index_bits
+    }
+
+    #[inline]
+    pub const fn index(&self, addr: usize, level: usize) -> usize {
+        let bits = if level == 0 {
+            self.root_index_bits
+        } else {
+            self.index_bits
+        };
+        (addr >> self.level_bits(level)) & mask_bits!(bits)
+    }
+
+    /// `addr`是否在输入地址范围内
+    ///
+    /// Whether `addr` is in the input range.
+    #[inline]
+    pub const fn contains(&self, addr: usize) -> bool {
+        if !self.sign_extend {
+            return addr >> self.input_bits() == 0;
+        }
+        let upper = addr >> (self.input_bits() - 1);
+        upper == 0 || upper == usize::MAX >> (self.input_bits() - 1)
+    }
+}
+
+/// 单个页表项在遍历中的含义，由各架构解码
+///
+/// What a descriptor means to the walk, decoded by each architecture.
+pub(crate) enum walk_entry_t {
+    Invalid,
+    Table(PAddr),
+    Leaf(PAddr),
+}
+
+/// 遍历一个阶段的页表，`table_to_pa`将页表地址转换为可以读取的物理地址
+///
+/// Walk the tables of one stage. `table_to_pa` turns the address of each table into a physical
+/// address, which for stage 1 goes through stage 2.
+fn walk_stage(
+    stage: translation_stage_t,
+    geometry: &walk_geometry_t,
+    root: PAddr,
+    addr: usize,
+    mut table_to_pa: impl FnMut(PAddr) -> Result<PAddr, nested_fault_t>,
+) -> Result<PAddr, nested_fault_t> {
+    let fault = |level| nested_fault_t { stage, level, addr };
+    // The index of each level only looks at its own bits, an address outside the input range
+    // would alias another one.
+    if !geometry.contains(addr) {
+        return Err(fault(0));
+    }
+    let mut table = root;
+    for level in 0..geometry.levels {
+        let pt = table_to_pa(table)?.to_pptr().get_mut_ptr::<PTE>();
+        let pte = unsafe { *pt.add(geometry.index(addr, level)) };
+        match pte.walk_entry(stage, geometry, level) {
+            walk_entry_t::Invalid => return Err(fault(level)),
+            walk_entry_t::Table(next) => table = next,
+            walk_entry_t::Leaf(base) => {
+                let offset_mask = mask_bits!(geometry.level_bits(level));
+                return Ok(paddr!((base.raw() & !offset_mask) | (addr & offset_mask)));
+            }
+        }
+    }
+    Err(fault(geometry.levels - 1))
+}
+
+/// 通过第二阶段页表将客户物理地址`ipa`翻译为物理地址
+///
+/// Translate the guest physical address `ipa` through the stage 2 tables rooted at `s2_root`.
+/// An address outside the IPA range faults at stage 2 level 0.
+pub fn translate_ipa(s2_root: PAddr, ipa: usize) -> Result<PAddr, nested_fault_t> {
+    walk_stage(translation_stage_t::Stage2, &S2_GEOMETRY, s2_root, ipa, Ok)
+}
+
+/// 通过客户机页表`s1_root`（客户物理地址，几何参数为`s1`）和第二阶段页表`s2_root`将客户虚拟地址翻译为物理地址
+///
+/// Translate the guest virtual address `gva` through the guest tables rooted at the guest
+/// physical address `s1_root` with the geometry `s1`, and then through the stage 2 tables
+/// rooted at `s2_root`. An address outside the guest's input range faults at stage 1 level 0.
+pub fn translate_gva(
+    s1_root: PAddr,
+    s1: &walk_geometry_t,
+    s2_root: PAddr,
+    gva: VPtr,
+) -> Result<PAddr, nested_fault_t> {
+    if s1.levels == 0 {
+        return translate_ipa(s2_root, gva.raw());
+    }
+    let ipa = walk_stage(
+        translation_stage_t::Stage1,
+        s1,
+        s1_root,
+        gva.raw(),
+        |table| translate_ipa(s2_root, table.raw()),
+    )?;
+    translate_ipa(s2_root, ipa.raw())
+}
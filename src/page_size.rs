@@ -0,0 +1,90 @@
+use crate::arch::{PAGE_SIZE_BITS, PAGE_SIZE_LEVELS};
+
+/// 各体系结构共用的页大小，枚举值即为`seL4`中`frame cap`的`size`字段
+///
+/// Page sizes shared by every architecture.
+/// The discriminant is the value stored in the size field of a frame cap,
+/// the bits and the level come from the size table of the current architecture.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PageSize {
+    /// riscv: 4 KiB page, aarch64: page of the translation granule
+    Small = 0,
+    /// riscv: 2 MiB mega page, aarch64: 2 MiB block (32 MiB / 512 MiB with 16 KiB / 64 KiB granules)
+    Large = 1,
+    /// riscv: 1 GiB giga page, aarch64: 1 GiB block, only with the 4 KiB granule
+    Huge = 2,
+    /// riscv with `riscv_svnapot`: 64 KiB NAPOT page, a group of 16 level 3 entries
+    #[cfg(all(target_arch = "riscv64", feature = "riscv_svnapot"))]
+    Napot64K = 3,
+}
+
+impl PageSize {
+    /// Get PageSize from the size field of a frame cap
+    #[inline]
+    pub const fn from_cap_size(size: usize) -> Option<Self> {
+        match size {
+            0 => Some(Self::Small),
+            1 => Some(Self::Large),
+            2 => Some(Self::Huge),
+            #[cfg(all(target_arch = "riscv64", feature = "riscv_svnapot"))]
+            3 => Some(Self::Napot64K),
+            _ => None,
+        }
+    }
+
+    /// Get PageSize from the bits of the region it maps,
+    /// e.g. the `ptBitsLeft` returned by `lookup_pt_slot`.
+    #[inline]
+    pub const fn from_bits(bits: usize) -> Option<Self> {
+        let mut i = 0;
+        while i < PAGE_SIZE_BITS.len() {
+            if PAGE_SIZE_BITS[i] == bits {
+                return Self::from_cap_size(i);
+            }
+            i += 1;
+        }
+        None
+    }
+
+    /// Get PageSize from the level of the page table walk holding its leaf entry.
+    #[inline]
+    pub const fn from_level(level: usize) -> Option<Self> {
+        let mut i = 0;
+        while i < PAGE_SIZE_LEVELS.len() {
+            if PAGE_SIZE_LEVELS[i] == level {
+                return Self::from_cap_size(i);
+            }
+            i += 1;
+        }
+        None
+    }
+
+    /// The value stored in the size field of a frame cap.
+    #[inline]
+    pub const fn to_cap_size(self) -> usize {
+        self as usize
+    }
+
+    /// The bits of the region mapped by a page of this size.
+    #[inline]
+    pub const fn bits(self) -> usize {
+        PAGE_SIZE_BITS[self as usize]
+    }
+
+    /// The level of the page table walk (0 is the root) holding the leaf entry.
+    #[inline]
+    pub const fn level(self) -> usize {
+        PAGE_SIZE_LEVELS[self as usize]
+    }
+
+    #[inline]
+    pub const fn size(self) -> usize {
+        bit!(self.bits())
+    }
+
+    /// Mask of the offset bits inside a page, an address is aligned if `addr & align_mask() == 0`
+    #[inline]
+    pub const fn align_mask(self) -> usize {
+        mask_bits!(self.bits())
+    }
+}
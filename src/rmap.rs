@@ -0,0 +1,425 @@
+//! 物理帧到其所有用户映射的反向映射
+//!
+//! Reverse mapping from a physical frame to all its user mappings, with the `reverse_map`
+//! feature.
+//!
+//! The page tables can only be walked from an (asid, vaddr), revoking a frame needs the other
+//! direction. `map_user_leaf` and `remap` record the mappings they make, other mappers of user
+//! frames call `rmap_insert`, `unmap_page` forgets the record, and `rmap_unmap_all` tears down
+//! every mapping of a frame.
+//!
+//! Records live in a fixed pool hashed by frame number. A teardown goes through `unmap_page`,
+//! which checks the page tables, so a record left behind by an unmapped page table is harmless.
+//! The records of a deleted ASID are dropped with it.
+use rel4_arch::basic::{PAddr, VPtr};
+use rel4_utils::no_lock::NoLock;
+use sel4_common::sel4_config::SEL4_PAGE_BITS;
+
+use crate::{asid_t, unmap_page, PageSize, PTE};
+
+/// 反向映射记录的数量上限
+///
+/// Number of mappings the reverse map can hold.
+pub const RMAP_ENTRIES: usize = 4096;
+const RMAP_BUCKET_BITS: usize = 10;
+/// End of a chain.
+const RMAP_NONE: usize = usize::MAX;
+
+/// 反向映射的错误
+///
+/// Errors of the reverse map.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum rmap_error_t {
+    /// 所有记录均已使用
+    ///
+    /// All `RMAP_ENTRIES` records are in use.
+    Full,
+}
+
+/// 帧的一个映射：地址空间、虚拟地址及映射的页大小
+///
+/// A mapping of a frame: the vspace, the virtual address and the size of the mapping.
+#[derive(Debug, Clone, Copy)]
+pub struct rmap_entry_t {
+    pub asid: asid_t,
+    pub vaddr: VPtr,
+    pub page_size: PageSize,
+}
+
+impl rmap_entry_t {
+    /// The level of the leaf entries of the mapping.
+    #[inline]
+    pub fn level(&self) -> usize {
+        self.page_size.level()
+    }
+}
+
+#[derive(Clone, Copy)]
+struct rmap_node_t {
+    pfn: usize,
+    asid: asid_t,
+    vaddr: usize,
+    size: usize,
+    next: usize,
+}
+
+impl rmap_node_t {
+    #[inline]
+    fn entry(&self) -> rmap_entry_t {
+        rmap_entry_t {
+            asid: self.asid,
+            vaddr: vptr!(self.vaddr),
+            page_size: PageSize::from_cap_size(self.size).unwrap(),
+        }
+    }
+}
+
+/// Chains of records hashed by frame number, the unused records are chained from `free`.
+struct rmap_t {
+    buckets: [usize; bit!(RMAP_BUCKET_BITS)],
+    nodes: [rmap_node_t; RMAP_ENTRIES],
+    free: usize,
+}
+
+impl rmap_t {
+    const fn new() -> Self {
+        let mut nodes = [rmap_node_t {
+            pfn: 0,
+            asid: 0,
+            vaddr: 0,
+            size: 0,
+            next: RMAP_NONE,
+        }; RMAP_ENTRIES];
+        let mut i = 0;
+        while i < RMAP_ENTRIES - 1 {
+            nodes[i].next = i + 1;
+            i += 1;
+        }
+        Self {
+            buckets: [RMAP_NONE; bit!(RMAP_BUCKET_BITS)],
+            nodes,
+            free: 0,
+        }
+    }
+
+    #[inline]
+    fn bucket(pfn: usize) -> usize {
+        // Fibonacci hashing, so that contiguous frames spread over the buckets.
+        pfn.wrapping_mul(0x9e37_79b9_7f4a_7c15) >> (usize::BITS as usize - RMAP_BUCKET_BITS)
+    }
+
+    /// Push the record `index` on the chain of `pfn`.
+    fn link(&mut self, index: usize, pfn: usize) {
+        let bucket = Self::bucket(pfn);
+        self.nodes[index].pfn = pfn;
+        self.nodes[index].next = self.buckets[bucket];
+        self.buckets[bucket] = index;
+    }
+
+    /// Remove `cur`, which follows `prev` in the chain of `bucket`, and return the next record.
+    fn unlink(&mut self, bucket: usize, prev: usize, cur: usize) -> usize {
+        let next = self.nodes[cur].next;
+        if prev == RMAP_NONE {
+            self.buckets[bucket] = next;
+        } else {
+            self.nodes[prev].next = next;
+        }
+        next
+    }
+
+    #[inline]
+    fn release(&mut self, index: usize) {
+        self.nodes[index].next = self.free;
+        self.free = index;
+    }
+
+    /// Unlink the first record of `pfn` matching `f` and return it.
+    fn take(&mut self, pfn: usize, mut f: impl FnMut(&rmap_node_t) -> bool) -> Option<usize> {
+        let bucket = Self::bucket(pfn);
+        let mut prev = RMAP_NONE;
+        let mut cur = self.buckets[bucket];
+        while cur != RMAP_NONE {
+            if self.nodes[cur].pfn == pfn && f(&self.nodes[cur]) {
+                self.unlink(bucket, prev, cur);
+                return Some(cur);
+            }
+            prev = cur;
+            cur = self.nodes[cur].next;
+        }
+        None
+    }
+
+    fn insert(
+        &mut self,
+        pfn: usize,
+        asid: asid_t,
+        vaddr: usize,
+        size: usize,
+    ) -> Result<(), rmap_error_t> {
+        let index = self.free;
+        if index == RMAP_NONE {
+            return Err(rmap_error_t::Full);
+        }
+        self.free = self.nodes[index].next;
+        self.nodes[index].asid = asid;
+        self.nodes[index].vaddr = vaddr;
+        self.nodes[index].size = size;
+        self.link(index, pfn);
+        Ok(())
+    }
+
+    fn remove(&mut self, pfn: usize, asid: asid_t, vaddr: usize) -> bool {
+        match self.take(pfn, |node| node.asid == asid && node.vaddr == vaddr) {
+            Some(index) => {
+                self.release(index);
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn move_to(&mut self, old_pfn: usize, new_pfn: usize, asid: asid_t, vaddr: usize) -> bool {
+        match self.take(old_pfn, |node| node.asid == asid && node.vaddr == vaddr) {
+            Some(index) => {
+                self.link(index, new_pfn);
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn remove_asids(&mut self, asid_base: asid_t, count: usize) {
+        for bucket in 0..bit!(RMAP_BUCKET_BITS) {
+            let mut prev = RMAP_NONE;
+            let mut cur = self.buckets[bucket];
+            while cur != RMAP_NONE {
+                let asid = self.nodes[cur].asid;
+                if asid >= asid_base && asid - asid_base < count {
+                    let next = self.unlink(bucket, prev, cur);
+                    self.release(cur);
+                    cur = next;
+                } else {
+                    prev = cur;
+                    cur = self.nodes[cur].next;
+                }
+            }
+        }
+    }
+
+    /// Call `f` on the records of `pfn` until it returns `false`.
+    fn for_each(&self, pfn: usize, mut f: impl FnMut(rmap_entry_t) -> bool) {
+        let mut cur = self.buckets[Self::bucket(pfn)];
+        while cur != RMAP_NONE {
+            if self.nodes[cur].pfn == pfn && !f(self.nodes[cur].entry()) {
+                return;
+            }
+            cur = self.nodes[cur].next;
+        }
+    }
+}
+
+/// 与页表一样只在持有内核大锁时访问，`NoLock`不提供多核间的保护
+///
+/// Like the page tables it describes, the pool is only touched with the big kernel lock held
+/// (or on a single core), `NoLock` gives no protection between cores by itself.
+static RMAP: NoLock<rmap_t> = NoLock::new(rmap_t::new());
+
+#[inline]
+fn frame_number(frame: PAddr) -> usize {
+    frame.raw() >> SEL4_PAGE_BITS
+}
+
+/// 记录`frame`在`asid`的`vaddr`处以`page_size`映射，映射用户帧后调用
+///
+/// Record that `frame` is mapped at `vaddr` of `asid` as a `page_size` leaf, to be called
+/// after mapping a user frame.
+pub fn rmap_insert(
+    frame: PAddr,
+    asid: asid_t,
+    vaddr: VPtr,
+    page_size: PageSize,
+) -> Result<(), rmap_error_t> {
+    RMAP.no_lock().insert(
+        frame_number(frame),
+        asid,
+        vaddr.raw(),
+        page_size.to_cap_size(),
+    )
+}
+
+/// 在`asid`的`vaddr`处将页表项`old`换为`new`前更新记录，失败时记录不变
+///
+/// Update the records before the entry mapping `vaddr` of `asid` goes from `old` to `new`, the
+/// records are unchanged on failure. Dropping the record of `old` first means a full pool can
+/// only refuse a mapping which adds a record.
+pub(crate) fn rmap_replace(
+    old: PTE,
+    new: PTE,
+    asid: asid_t,
+    vaddr: VPtr,
+    page_size: PageSize,
+) -> Result<(), rmap_error_t> {
+    if old.is_leaf() {
+        rmap_remove(old.get_page_base_address(), asid, vaddr);
+    }
+    if new.is_leaf() {
+        return rmap_insert(new.get_page_base_address(), asid, vaddr, page_size);
+    }
+    Ok(())
+}
+
+/// 删除`frame`在`asid`的`vaddr`处的映射记录，返回记录是否存在
+///
+/// Forget the mapping of `frame` at `vaddr` of `asid`, returns whether it was recorded.
+pub fn rmap_remove(frame: PAddr, asid: asid_t, vaddr: VPtr) -> bool {
+    RMAP.no_lock()
+        .remove(frame_number(frame), asid, vaddr.raw())
+}
+
+/// 将`asid`的`vaddr`处的映射记录从`old_frame`移到`new_frame`，不需要新的记录
+///
+/// Move the record of the mapping at `vaddr` of `asid` from `old_frame` to `new_frame`, which
+/// cannot fail for lack of records.
+pub fn rmap_move(old_frame: PAddr, new_frame: PAddr, asid: asid_t, vaddr: VPtr) -> bool {
+    RMAP.no_lock().move_to(
+        frame_number(old_frame),
+        frame_number(new_frame),
+        asid,
+        vaddr.raw(),
+    )
+}
+
+/// 删除`[asid_base, asid_base + count)`中所有地址空间的映射记录，删除`asid`时调用
+///
+/// Forget every mapping of the ASIDs in `[asid_base, asid_base + count)`, to be called when
+/// they are deleted.
+pub fn rmap_remove_asids(asid_base: asid_t, count: usize) {
+    RMAP.no_lock().remove_asids(asid_base, count);
+}
+
+/// 对`frame`的每个映射记录调用`f`
+///
+/// Call `f` on every recorded mapping of `frame`, `f` must not change the reverse map.
+pub fn rmap_for_each(frame: PAddr, mut f: impl FnMut(rmap_entry_t)) {
+    RMAP.no_lock().for_each(frame_number(frame), |entry| {
+        f(entry);
+        true
+    });
+}
+
+fn first_entry(pfn: usize) -> Option<rmap_entry_t> {
+    let mut first = None;
+    RMAP.no_lock().for_each(pfn, |entry| {
+        first = Some(entry);
+        false
+    });
+    first
+}
+
+/// 解除`frame`的所有映射并删除其记录，返回删除的记录数
+///
+/// Unmap every recorded mapping of `frame` and forget them, returns how many records were
+/// dropped. Records which no longer match the page tables are dropped without unmapping.
+pub fn rmap_unmap_all(frame: PAddr) -> usize {
+    let mut count = 0;
+    while let Some(entry) = first_entry(frame_number(frame)) {
+        // A failed lookup or a rejected address only means there is nothing left to unmap.
+        let _ = unmap_page(
+            entry.page_size.to_cap_size(),
+            entry.asid,
+            entry.vaddr,
+            frame.to_pptr(),
+        );
+        // `unmap_page` only forgets the records it unmapped.
+        rmap_remove(frame, entry.asid, entry.vaddr);
+        count += 1;
+    }
+    count
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SMALL: usize = PageSize::Small.to_cap_size();
+
+    fn count(rmap: &rmap_t, pfn: usize) -> usize {
+        let mut count = 0;
+        rmap.for_each(pfn, |_| {
+            count += 1;
+            true
+        });
+        count
+    }
+
+    #[test]
+    fn insert_and_remove() {
+        let mut rmap = rmap_t::new();
+        assert_eq!(rmap.insert(7, 1, 0x1000, SMALL), Ok(()));
+        assert_eq!(rmap.insert(7, 2, 0x2000, SMALL), Ok(()));
+        // The records of other frames are not reported, whatever their bucket.
+        assert_eq!(rmap.insert(8, 1, 0x3000, SMALL), Ok(()));
+        assert_eq!(count(&rmap, 7), 2);
+        rmap.for_each(8, |entry| {
+            assert_eq!((entry.asid, entry.vaddr.raw()), (1, 0x3000));
+            assert_eq!(entry.page_size, PageSize::Small);
+            true
+        });
+
+        assert!(!rmap.remove(7, 1, 0x2000));
+        assert!(rmap.remove(7, 1, 0x1000));
+        assert!(!rmap.remove(7, 1, 0x1000));
+        assert_eq!(count(&rmap, 7), 1);
+        assert!(rmap.remove(7, 2, 0x2000));
+        assert_eq!(count(&rmap, 7), 0);
+        assert_eq!(count(&rmap, 8), 1);
+    }
+
+    #[test]
+    fn move_keeps_the_record() {
+        let mut rmap = rmap_t::new();
+        rmap.insert(7, 1, 0x1000, PageSize::Large.to_cap_size())
+            .unwrap();
+        assert!(!rmap.move_to(7, 9, 1, 0x2000));
+        assert!(rmap.move_to(7, 9, 1, 0x1000));
+        assert_eq!(count(&rmap, 7), 0);
+        rmap.for_each(9, |entry| {
+            assert_eq!((entry.asid, entry.vaddr.raw()), (1, 0x1000));
+            assert_eq!(entry.page_size, PageSize::Large);
+            true
+        });
+        assert_eq!(count(&rmap, 9), 1);
+    }
+
+    #[test]
+    fn remove_asids_drops_their_records() {
+        let mut rmap = rmap_t::new();
+        for asid in 0..8 {
+            rmap.insert(7, asid, 0x1000, SMALL).unwrap();
+            rmap.insert(100 + asid, asid, 0x1000, SMALL).unwrap();
+        }
+        rmap.remove_asids(2, 4);
+        assert_eq!(count(&rmap, 7), 4);
+        rmap.for_each(7, |entry| {
+            assert!(!(2..6).contains(&entry.asid));
+            true
+        });
+        for asid in 0..8 {
+            let expected = (!(2..6).contains(&asid)) as usize;
+            assert_eq!(count(&rmap, 100 + asid), expected);
+        }
+    }
+
+    #[test]
+    fn full_until_a_record_is_released() {
+        let mut rmap = rmap_t::new();
+        for i in 0..RMAP_ENTRIES {
+            assert_eq!(rmap.insert(i, 1, i << SEL4_PAGE_BITS, SMALL), Ok(()));
+        }
+        assert_eq!(rmap.insert(0, 2, 0, SMALL), Err(rmap_error_t::Full));
+        assert!(rmap.remove(5, 1, 5 << SEL4_PAGE_BITS));
+        assert_eq!(rmap.insert(0, 2, 0, SMALL), Ok(()));
+        assert_eq!(count(&rmap, 0), 2);
+        assert_eq!(rmap.insert(1, 2, 0, SMALL), Err(rmap_error_t::Full));
+    }
+}
@@ -2,7 +2,9 @@ use core::fmt::Debug;
 
 use sel4_common::{structures::exception_t, structures_gen::lookup_fault};
 
-use crate::PTE;
+#[cfg(feature = "reverse_map")]
+use crate::rmap_error_t;
+use crate::{vaddr_error_t, PTE};
 
 /// 进程对应的asid所属的类型
 pub type asid_t = usize;
@@ -24,9 +26,71 @@ pub struct findVSpaceForASID_ret {
     pub lookup_fault: Option<lookup_fault>,
 }
 
-/// 进行系统调用时，应用程序向内核传递信息的消息格式
+/// 建立用户映射失败的原因，失败时页表不变
+///
+/// Why a user mapping failed, the page tables are left unchanged.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum map_error_t {
+    /// 地址不是该页大小的合法用户地址
+    ///
+    /// The address is not a valid user address for the page size.
+    Vaddr(vaddr_error_t),
+    /// 反向映射已满，无法记录该映射
+    ///
+    /// The reverse map has no record left for the mapping.
+    #[cfg(feature = "reverse_map")]
+    Rmap(rmap_error_t),
+}
+
+impl From<vaddr_error_t> for map_error_t {
+    fn from(err: vaddr_error_t) -> Self {
+        Self::Vaddr(err)
+    }
+}
+
+#[cfg(feature = "reverse_map")]
+impl From<rmap_error_t> for map_error_t {
+    fn from(err: rmap_error_t) -> Self {
+        Self::Rmap(err)
+    }
+}
+
+/// 解除映射失败的原因
+///
+/// Why an unmap failed.
+#[derive(Clone)]
+pub enum unmap_error_t {
+    /// 找不到`asid`对应的地址空间
+    ///
+    /// No vspace is bound to the ASID.
+    Lookup(lookup_fault),
+    /// 地址不是合法的用户地址
+    ///
+    /// The address is not a valid user address.
+    Vaddr(vaddr_error_t),
+}
+
+impl From<vaddr_error_t> for unmap_error_t {
+    fn from(err: vaddr_error_t) -> Self {
+        Self::Vaddr(err)
+    }
+}
+
+/// `vm_attributes_t`转换失败的原因
+///
+/// Why a word was rejected as `vm_attributes_t`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum vm_attributes_error_t {
+    /// 设置了当前架构未定义的位
+    ///
+    /// A bit not defined on this architecture is set.
+    ReservedBits,
+}
+
+/// 进行系统调用时，应用程序向内核传递信息的消息格式，各位的含义由架构决定（见`arch::*::structures`）
 ///
 /// vm_attributes_t is a message type. When program pass message to kernel , it uses vm_attributes_t.
+/// The meaning of each bit is architecture specific, see `VALID_BITS` and the arch accessors.
 #[repr(C)]
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub struct vm_attributes_t(pub(crate) usize);
@@ -40,16 +104,72 @@ impl vm_attributes_t {
         Self::new(w)
     }
 
+    /// 转换系统调用传入的`w`，保留位非零时返回错误，内核应将其报告为`seL4_InvalidArgument`
+    ///
+    /// Convert the word `w` passed by a system call. A reserved bit set is an error, to be
+    /// reported as `seL4_InvalidArgument`.
+    pub fn try_from_word(w: usize) -> Result<Self, vm_attributes_error_t> {
+        let attr = Self::from_word(w);
+        if !attr.is_valid() {
+            return Err(vm_attributes_error_t::ReservedBits);
+        }
+        Ok(attr)
+    }
+
+    pub fn is_valid(&self) -> bool {
+        self.0 & !Self::VALID_BITS == 0
+    }
+
     pub fn get_execute_never(&self) -> usize {
-        self.0 & 0x1usize
+        (self.0 & Self::EXECUTE_NEVER != 0) as usize
     }
 
     pub fn set_execute_never(&mut self, v64: usize) {
-        self.0 &= !0x1usize;
-        self.0 |= (v64 << 0) & 0x1usize;
+        self.0 &= !Self::EXECUTE_NEVER;
+        if v64 != 0 {
+            self.0 |= Self::EXECUTE_NEVER;
+        }
     }
 
     pub fn get_page_cacheable(&self) -> usize {
-        self.0 & 0x1
+        self.is_page_cacheable() as usize
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn try_from_word_rejects_reserved_bits() {
+        assert_eq!(
+            vm_attributes_t::try_from_word(vm_attributes_t::VALID_BITS),
+            Ok(vm_attributes_t(vm_attributes_t::VALID_BITS))
+        );
+        assert_eq!(vm_attributes_t::try_from_word(0), Ok(vm_attributes_t(0)));
+        let reserved = !vm_attributes_t::VALID_BITS;
+        assert_eq!(
+            vm_attributes_t::try_from_word(1 << reserved.trailing_zeros()),
+            Err(vm_attributes_error_t::ReservedBits)
+        );
+        assert_eq!(
+            vm_attributes_t::try_from_word(usize::MAX),
+            Err(vm_attributes_error_t::ReservedBits)
+        );
+        assert_eq!(
+            vm_attributes_t::from_word(usize::MAX),
+            vm_attributes_t(usize::MAX)
+        );
+    }
+
+    #[test]
+    fn execute_never_round_trips() {
+        let mut attr = vm_attributes_t::new(0);
+        attr.set_execute_never(1);
+        assert_eq!(attr.get_execute_never(), 1);
+        assert_eq!(attr.0, vm_attributes_t::EXECUTE_NEVER);
+        attr.set_execute_never(0);
+        assert_eq!(attr.get_execute_never(), 0);
+        assert_eq!(attr.0, 0);
     }
 }
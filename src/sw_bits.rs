@@ -0,0 +1,158 @@
+//! 页表项中留给软件使用的位
+//!
+//! Software-reserved bits of page table entries: RSW bits 8-9 on riscv, descriptor bits 55-57
+//! on aarch64 (bit 58 is taken by the 4k page tag of this crate). They are ignored by the
+//! hardware, so state such as copy-on-write or logging markers can be kept in the entry itself.
+//!
+//! `update` stores the new entry as is, `update_keep_sw_bits` keeps them across a change of a
+//! mapping of the same frame.
+//!
+//! The crate only takes bits for the features that are enabled, from the lowest one up: dirty
+//! logging with `hypervisor`, copy-on-write with `cow`. A riscv build with one of them leaves
+//! RSW bit 9 to callers, one with both has none left.
+use core::sync::atomic::Ordering;
+
+use rel4_arch::basic::VPtr;
+
+use crate::arch::{PTE_SW_BITS, PTE_SW_BITS_SHIFT};
+use crate::{asid_t, check_user_frame, map_error_t, PageSize, PTE};
+
+/// 本 crate 使用的软件位，只有启用的功能才占用一位
+///
+/// Software bits used by this crate, a bit is only taken when its feature is enabled.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum pte_sw_bit_t {
+    /// 被脏页记录写保护的叶子
+    ///
+    /// Leaf write-protected by dirty logging.
+    #[cfg(feature = "hypervisor")]
+    DirtyLog,
+    /// 写时复制共享的叶子
+    ///
+    /// Leaf shared copy-on-write.
+    #[cfg(feature = "cow")]
+    Cow,
+}
+
+impl pte_sw_bit_t {
+    /// 本 crate 占用的软件位数，更高的位留给调用者
+    ///
+    /// Number of software bits taken by this crate, the ones above are free for callers.
+    pub const RESERVED: usize =
+        cfg!(feature = "hypervisor") as usize + cfg!(feature = "cow") as usize;
+
+    /// 该软件位在软件位中的序号
+    ///
+    /// Index of the bit among the software bits.
+    #[inline]
+    pub const fn index(self) -> usize {
+        match self {
+            #[cfg(feature = "hypervisor")]
+            Self::DirtyLog => 0,
+            #[cfg(feature = "cow")]
+            Self::Cow => cfg!(feature = "hypervisor") as usize,
+        }
+    }
+}
+
+const _: () = assert!(pte_sw_bit_t::RESERVED <= PTE_SW_BITS);
+
+impl PTE {
+    /// 可供软件使用的位数
+    ///
+    /// Number of software bits.
+    pub const SW_BITS: usize = PTE_SW_BITS;
+
+    #[inline]
+    pub const fn get_sw_bits(&self) -> usize {
+        (self.0 >> PTE_SW_BITS_SHIFT) & mask_bits!(PTE_SW_BITS)
+    }
+
+    /// 替换软件位后的页表项
+    ///
+    /// The entry with its software bits replaced by `bits`.
+    #[inline]
+    pub const fn with_sw_bits(&self, bits: usize) -> Self {
+        assert!(bits & !mask_bits!(PTE_SW_BITS) == 0);
+        PTE((self.0 & !(mask_bits!(PTE_SW_BITS) << PTE_SW_BITS_SHIFT))
+            | (bits << PTE_SW_BITS_SHIFT))
+    }
+
+    #[inline]
+    pub fn set_sw_bits(&mut self, bits: usize) {
+        let _ = self.fetch_update(|pte| Some(pte.with_sw_bits(bits)));
+    }
+
+    #[inline]
+    pub const fn get_sw_bit(&self, sw_bit: pte_sw_bit_t) -> bool {
+        self.get_sw_bits() & bit!(sw_bit.index()) != 0
+    }
+
+    /// 设置或清除一个软件位后的页表项
+    ///
+    /// The entry with one software bit set or cleared.
+    #[inline]
+    pub const fn with_sw_bit(&self, sw_bit: pte_sw_bit_t, value: bool) -> Self {
+        let bits = self.get_sw_bits() & !bit!(sw_bit.index());
+        self.with_sw_bits(bits | ((value as usize) << sw_bit.index()))
+    }
+
+    /// 与`map_user_leaf`相同地将`asid`地址空间中`vaddr`处的用户页表项换为`pte`，但保留原页表项的软件位
+    ///
+    /// Replace the user leaf mapping `vaddr` of `asid` with `pte` of `page_size` like
+    /// `map_user_leaf`, keeping the software bits of the old one. Nothing is changed at an invalid
+    /// or misaligned address, or when the reverse map is full.
+    #[inline]
+    #[cfg_attr(not(feature = "reverse_map"), allow(unused_variables))]
+    pub fn remap(
+        &mut self,
+        pte: Self,
+        page_size: PageSize,
+        asid: asid_t,
+        vaddr: VPtr,
+    ) -> Result<(), map_error_t> {
+        check_user_frame(vaddr, page_size)?;
+        #[cfg(feature = "reverse_map")]
+        crate::rmap::rmap_replace(self.load(), pte, asid, vaddr, page_size)?;
+        let _ = self.fetch_update(|old| Some(pte.with_sw_bits(old.get_sw_bits())));
+        Ok(())
+    }
+
+    /// 与`update`相同地替换页表项，但两者为同一帧的叶子时（如修改映射权限）保留软件位，
+    /// 写时复制或被脏页记录写保护的叶子保持只读
+    ///
+    /// Replace the entry like `update`. When both are leaves of the same frame, e.g. the rights
+    /// of a mapping change, the software bits are kept and a leaf that is copy-on-write or
+    /// write-protected for dirty logging stays read-only. Any other entry is stored as is.
+    #[inline]
+    pub fn update_keep_sw_bits(&mut self, pte: Self) {
+        self.prepare_update();
+        let pte = self.updated_with(pte);
+        self.atomic().store(pte.0, Ordering::Release);
+        self.sync_update();
+    }
+
+    /// `update_keep_sw_bits`在本页表项处写入的页表项
+    ///
+    /// The entry `update_keep_sw_bits` stores over this one.
+    fn updated_with(&self, pte: Self) -> Self {
+        if !self.is_leaf()
+            || !pte.is_leaf()
+            || self.get_page_base_address().raw() != pte.get_page_base_address().raw()
+        {
+            return pte;
+        }
+        let pte = pte.with_sw_bits(self.get_sw_bits());
+        #[cfg(feature = "hypervisor")]
+        let pte = if pte.get_sw_bit(pte_sw_bit_t::DirtyLog) {
+            pte.dirty_log_protected().unwrap_or(pte)
+        } else {
+            pte
+        };
+        #[cfg(feature = "cow")]
+        if pte.get_sw_bit(pte_sw_bit_t::Cow) {
+            return pte.cow_protected().unwrap_or(pte);
+        }
+        pte
+    }
+}
@@ -1,15 +1,77 @@
 use core::ops::{Deref, DerefMut};
 
-use sel4_common::{sel4_config::PT_INDEX_BITS, utils::pageBitsForSize};
+use sel4_common::{arch::vm_rights_t, sel4_config::PT_INDEX_BITS};
+
+use crate::arch::lookup_vspace_slot;
+use crate::{asid_t, PageSize, PTE};
 
 #[no_mangle]
 pub fn check_vp_alignment(sz: usize, w: usize) -> bool {
-    w & mask_bits!(pageBitsForSize(sz)) == 0
+    match PageSize::from_cap_size(sz) {
+        Some(page_size) => w & page_size.align_mask() == 0,
+        None => false,
+    }
+}
+
+/// Convert the rights word stored in a frame cap back to `vm_rights_t`.
+///
+/// Unknown values are treated as `VMKernelOnly` so they never grant user access.
+pub(crate) fn vm_rights_from_word(w: usize) -> vm_rights_t {
+    if w == vm_rights_t::VMReadWrite as usize {
+        vm_rights_t::VMReadWrite
+    } else if w == vm_rights_t::VMReadOnly as usize {
+        vm_rights_t::VMReadOnly
+    } else {
+        vm_rights_t::VMKernelOnly
+    }
+}
+
+/// Call `f` on every leaf covering `[start, end)`, returns whether any of them changed.
+pub(crate) fn for_each_leaf(
+    vspace_root: *mut PTE,
+    start: usize,
+    end: usize,
+    mut f: impl FnMut(&mut PTE, usize, usize) -> bool,
+) -> bool {
+    let mut changed = false;
+    let mut addr = start;
+    while addr < end {
+        let lu_ret = lookup_vspace_slot(vspace_root, vptr!(addr));
+        let leaf_start = addr & !mask_bits!(lu_ret.ptBitsLeft);
+        let leaf_end = leaf_start + bit!(lu_ret.ptBitsLeft);
+        changed |= f(unsafe { &mut *lu_ret.ptSlot }, leaf_start, leaf_end);
+        addr = leaf_end;
+    }
+    changed
+}
+
+/// Like `for_each_leaf` for callers modifying the leaves of the vspace of `asid`, the hardware
+/// group of each leaf is split before `f` is called.
+pub(crate) fn for_each_leaf_mut(
+    vspace_root: *mut PTE,
+    asid: asid_t,
+    start: usize,
+    end: usize,
+    mut f: impl FnMut(&mut PTE, usize, usize) -> bool,
+) -> bool {
+    for_each_leaf(vspace_root, start, end, |pte, leaf_start, leaf_end| {
+        let size_bits = (leaf_end - leaf_start).trailing_zeros() as usize;
+        pte.split_group(asid, vptr!(leaf_start), size_bits);
+        f(pte, leaf_start, leaf_end)
+    })
 }
 
 pub const PAGE_ALIGNED_LEN: usize = bit!(PT_INDEX_BITS);
 
-#[repr(align(4096))]
+/// 按页（`aarch64`下为翻译粒度）对齐的页表
+///
+/// A table aligned to the page size, the translation granule on aarch64.
+#[cfg_attr(
+    not(any(feature = "arm_granule_16k", feature = "arm_granule_64k")),
+    repr(align(4096))
+)]
+#[cfg_attr(feature = "arm_granule_16k", repr(align(16384)))]
+#[cfg_attr(feature = "arm_granule_64k", repr(align(65536)))]
 #[derive(Clone, Copy)]
 pub struct PageAligned<T>([T; PAGE_ALIGNED_LEN]);
 
@@ -0,0 +1,160 @@
+//! 用户虚拟地址的检查
+//!
+//! Validation of user virtual addresses, done before a vspace is walked with them.
+//!
+//! The index functions of the walks only look at the bits of each level, so an address outside
+//! the user range would silently alias a user one. The user range is `[0, USER_TOP)`:
+//! - riscv: the lower canonical half of Sv39 / Sv48, the upper one belongs to the kernel,
+//! - aarch64: the TTBR0 range, the TTBR1 one belongs to the kernel,
+//! - aarch64 with `hypervisor`: the IPA range of the stage 2 vspaces, which has no kernel half,
+//! - riscv with `hypervisor`: the guest physical range of the G-stage vspaces, which has none
+//!   either.
+//!
+//! Every other address is either in the kernel half or not canonical.
+use rel4_arch::basic::VPtr;
+use sel4_common::sel4_config::SEL4_PAGE_BITS;
+
+use crate::arch::{KERNEL_HALF_BASE, USER_TOP};
+use crate::PageSize;
+
+/// 用户虚拟地址检查失败的原因
+///
+/// Why a user virtual address was rejected.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum vaddr_error_t {
+    /// 地址属于内核地址空间
+    ///
+    /// The address is in the kernel half.
+    KernelHalf,
+    /// 地址不在任何一半中（非规范地址，或超出客户物理地址范围）
+    ///
+    /// The address is in neither half: not canonical, or beyond the IPA range.
+    NonCanonical,
+    /// 地址未按页大小对齐
+    ///
+    /// The address is not aligned to the page size.
+    Misaligned,
+    /// 范围的结束地址小于起始地址
+    ///
+    /// The end of the range is below its start.
+    InvalidRange,
+    /// 与范围一同给出的位图不足以覆盖该范围
+    ///
+    /// The bitmap given with the range is too short to cover it.
+    BitmapTooShort,
+}
+
+/// 检查`vaddr`是否为用户地址
+///
+/// Check that `vaddr` is a user address.
+#[inline]
+pub fn check_user_vaddr(vaddr: VPtr) -> Result<(), vaddr_error_t> {
+    let vaddr = vaddr.raw();
+    if vaddr < USER_TOP {
+        return Ok(());
+    }
+    match KERNEL_HALF_BASE {
+        Some(base) if vaddr >= base => Err(vaddr_error_t::KernelHalf),
+        _ => Err(vaddr_error_t::NonCanonical),
+    }
+}
+
+/// 检查`vaddr`处大小为`page_size`的映射是否对齐且完全位于用户地址空间
+///
+/// Check that a `page_size` mapping at `vaddr` is aligned and lies in the user range.
+#[inline]
+pub fn check_user_frame(vaddr: VPtr, page_size: PageSize) -> Result<(), vaddr_error_t> {
+    check_user_vaddr(vaddr)?;
+    if vaddr.raw() & page_size.align_mask() != 0 {
+        return Err(vaddr_error_t::Misaligned);
+    }
+    // The user range is aligned to every page size, so the whole mapping fits.
+    Ok(())
+}
+
+/// 检查页对齐的范围`[start, end)`是否完全位于用户地址空间，空范围总是合法的
+///
+/// Check that the page aligned range `[start, end)` lies in the user range, an empty range is
+/// always valid.
+pub fn check_user_range(start: VPtr, end: VPtr) -> Result<(), vaddr_error_t> {
+    if end.raw() < start.raw() {
+        return Err(vaddr_error_t::InvalidRange);
+    }
+    if (start.raw() | end.raw()) & mask_bits!(SEL4_PAGE_BITS) != 0 {
+        return Err(vaddr_error_t::Misaligned);
+    }
+    if start.raw() == end.raw() {
+        return Ok(());
+    }
+    check_user_vaddr(start)?;
+    check_user_vaddr(vptr!(end.raw() - 1))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn user_range_bounds() {
+        assert_eq!(check_user_vaddr(vptr!(0)), Ok(()));
+        assert_eq!(check_user_vaddr(vptr!(USER_TOP - 1)), Ok(()));
+        assert!(check_user_vaddr(vptr!(USER_TOP)).is_err());
+        match KERNEL_HALF_BASE {
+            Some(base) => {
+                assert_eq!(
+                    check_user_vaddr(vptr!(USER_TOP)),
+                    Err(vaddr_error_t::NonCanonical)
+                );
+                assert_eq!(
+                    check_user_vaddr(vptr!(base - 1)),
+                    Err(vaddr_error_t::NonCanonical)
+                );
+                assert_eq!(
+                    check_user_vaddr(vptr!(base)),
+                    Err(vaddr_error_t::KernelHalf)
+                );
+                assert_eq!(
+                    check_user_vaddr(vptr!(usize::MAX)),
+                    Err(vaddr_error_t::KernelHalf)
+                );
+            }
+            None => assert_eq!(
+                check_user_vaddr(vptr!(usize::MAX)),
+                Err(vaddr_error_t::NonCanonical)
+            ),
+        }
+    }
+
+    #[test]
+    fn user_frame_alignment() {
+        let large = PageSize::Large;
+        assert_eq!(check_user_frame(vptr!(large.size()), large), Ok(()));
+        assert_eq!(
+            check_user_frame(vptr!(large.size() + PageSize::Small.size()), large),
+            Err(vaddr_error_t::Misaligned)
+        );
+        assert_eq!(
+            check_user_frame(vptr!(USER_TOP - large.size()), large),
+            Ok(())
+        );
+        assert!(check_user_frame(vptr!(USER_TOP), large).is_err());
+    }
+
+    #[test]
+    fn user_range_checks() {
+        let page = bit!(SEL4_PAGE_BITS);
+        assert_eq!(check_user_range(vptr!(page), vptr!(page)), Ok(()));
+        assert_eq!(check_user_range(vptr!(0), vptr!(USER_TOP)), Ok(()));
+        assert_eq!(
+            check_user_range(vptr!(2 * page), vptr!(page)),
+            Err(vaddr_error_t::InvalidRange)
+        );
+        assert_eq!(
+            check_user_range(vptr!(0), vptr!(page + 1)),
+            Err(vaddr_error_t::Misaligned)
+        );
+        assert!(check_user_range(vptr!(USER_TOP - page), vptr!(USER_TOP + page)).is_err());
+        // An empty range is valid wherever it is.
+        assert_eq!(check_user_range(vptr!(USER_TOP), vptr!(USER_TOP)), Ok(()));
+    }
+}